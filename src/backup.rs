@@ -0,0 +1,431 @@
+use crate::db::{
+    ChatId, ChatMessage, ChatName, ChatSettings, CodeUser, HonorSnapshot, KataMapping,
+    RawChatMessage,
+};
+use crate::error::{BackupError, MainError};
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Version written to new archives, bump it whenever their shape changes
+/// and add an upgrade from the previous version to `UPGRADES`
+pub const ARCHIVE_VERSION: u32 = 5;
+
+type Upgrade = fn(&mut Value) -> Result<(), MainError>;
+
+/// Upgrade `i` turns an archive of version `i + 1` into version `i + 2`
+const UPGRADES: &[Upgrade] = &[
+    drop_imports_by_title,
+    parse_messages,
+    add_kata_mappings,
+    add_honor_history,
+];
+
+/// Everything needed to rebuild the bot's state in an empty database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Archive {
+    pub version: u32,
+    pub created_at: i64,
+    pub chats: Vec<ChatBackup>,
+    pub imported_chats: Vec<ImportedChatBackup>,
+    pub honor_history: BTreeMap<String, Vec<HonorSnapshot>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatBackup {
+    pub id: ChatId,
+    pub title: Option<ChatName>,
+    pub users: Vec<CodeUser>,
+    pub messages: Vec<ChatMessage>,
+    pub settings: ChatSettings,
+    pub kata_mappings: BTreeMap<String, KataMapping>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportedChatBackup {
+    pub id: ChatId,
+    pub imported: bool,
+    pub messages: Vec<ChatMessage>,
+}
+
+pub fn export(db: &dyn Storage) -> Result<Archive, MainError> {
+    let mut chats = Vec::new();
+    for id in db.chats()? {
+        let mut users: Vec<_> = db.get_users(id)?.into_iter().map(|(_, u)| u).collect();
+        users.sort_by_key(|u| u.telegram_id.0);
+        chats.push(ChatBackup {
+            id,
+            title: db.chat_title(id)?,
+            users,
+            messages: db.get_messages(id)?,
+            settings: db.get_settings(id)?,
+            kata_mappings: db.kata_mappings(id)?,
+        });
+    }
+    let mut imported_chats = Vec::new();
+    for id in db.imported_chats()? {
+        imported_chats.push(ImportedChatBackup {
+            id,
+            imported: db.is_chat_imported(id)?,
+            messages: db.get_imported_messages(id)?,
+        });
+    }
+    let mut honor_history = BTreeMap::new();
+    for name in db.honor_history_names()? {
+        let history = db.get_honor_history(&name, i64::MIN)?;
+        honor_history.insert(name, history);
+    }
+    Ok(Archive {
+        version: ARCHIVE_VERSION,
+        created_at: chrono::Utc::now().timestamp(),
+        chats,
+        imported_chats,
+        honor_history,
+    })
+}
+
+/// Checks the archive is of a supported version and has no duplicate
+/// chats, users, messages or honor snapshots
+pub fn validate(archive: &Archive) -> Result<(), BackupError> {
+    fn unique<T: Eq + std::hash::Hash>(
+        items: impl IntoIterator<Item = T>,
+        what: impl FnOnce() -> String,
+    ) -> Result<(), BackupError> {
+        let mut seen = HashSet::new();
+        if items.into_iter().all(|item| seen.insert(item)) {
+            Ok(())
+        } else {
+            Err(BackupError::Invalid(format!("duplicate {}", what())))
+        }
+    }
+
+    if archive.version != ARCHIVE_VERSION {
+        return Err(BackupError::UnsupportedVersion {
+            found: archive.version as u64,
+            supported: ARCHIVE_VERSION,
+        });
+    }
+    unique(archive.chats.iter().map(|c| c.id), || "chats".to_owned())?;
+    unique(archive.imported_chats.iter().map(|c| c.id), || {
+        "imported chats".to_owned()
+    })?;
+    for chat in archive.chats.iter() {
+        unique(chat.users.iter().map(|u| u.telegram_id), || {
+            format!("users in chat {}", chat.id.0)
+        })?;
+        unique(chat.messages.iter().map(|m| m.id), || {
+            format!("messages in chat {}", chat.id.0)
+        })?;
+    }
+    for chat in archive.imported_chats.iter() {
+        unique(chat.messages.iter().map(|m| m.id), || {
+            format!("messages in imported chat {}", chat.id.0)
+        })?;
+    }
+    for (name, history) in archive.honor_history.iter() {
+        unique(history.iter().map(|s| s.taken_at), || {
+            format!("honor snapshots of {}", name)
+        })?;
+    }
+    Ok(())
+}
+
+/// Loads a valid archive into a database without any chats or honor history
+pub fn restore(db: &dyn Storage, archive: &Archive) -> Result<(), MainError> {
+    validate(archive)?;
+    if !db.chats()?.is_empty()
+        || !db.imported_chats()?.is_empty()
+        || !db.honor_history_names()?.is_empty()
+    {
+        Err(BackupError::NotEmpty)?
+    }
+    for chat in archive.chats.iter() {
+        for user in chat.users.iter() {
+            db.add_user(chat.id, user.clone())?;
+        }
+        for msg in chat.messages.iter() {
+            db.add_message(chat.id, msg.clone())?;
+        }
+        db.set_settings(chat.id, chat.settings.clone())?;
+        if let Some(title) = chat.title.clone() {
+            db.set_chat_title(chat.id, title)?;
+        }
+        for (name, mapping) in chat.kata_mappings.iter() {
+            db.set_kata_mapping(chat.id, name.clone(), mapping.clone())?;
+        }
+    }
+    for chat in archive.imported_chats.iter() {
+        for msg in chat.messages.iter() {
+            db.add_imported_message(chat.id, msg.clone())?;
+        }
+        db.set_imported(chat.id, chat.imported)?;
+    }
+    for (name, history) in archive.honor_history.iter() {
+        for snapshot in history.iter() {
+            db.add_honor_snapshot(name, snapshot.clone())?;
+        }
+    }
+    log::info!(
+        "restored {} chats, {} imported chats and honor history of {} users from a backup made at {}",
+        archive.chats.len(),
+        archive.imported_chats.len(),
+        archive.honor_history.len(),
+        archive.created_at
+    );
+    Ok(())
+}
+
+pub fn save(archive: &Archive, path: &Path) -> Result<(), MainError> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(archive)?)?;
+    Ok(())
+}
+
+/// Reads an archive, checking its version before the rest of its shape and
+/// upgrading archives written by older versions of the bot
+pub fn load(path: &Path) -> Result<Archive, MainError> {
+    let json: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    Ok(serde_json::from_value(upgrade(json)?)?)
+}
+
+fn upgrade(mut json: Value) -> Result<Value, MainError> {
+    let version = match json.get("version").and_then(|v| v.as_u64()) {
+        Some(version) if (1..=ARCHIVE_VERSION as u64).contains(&version) => version,
+        Some(found) => Err(BackupError::UnsupportedVersion {
+            found,
+            supported: ARCHIVE_VERSION,
+        })?,
+        None => Err(BackupError::Invalid("no archive version".to_owned()))?,
+    };
+    for (i, upgrade) in UPGRADES.iter().enumerate().skip(version as usize - 1) {
+        log::info!("upgrading backup archive to version {}", i + 2);
+        upgrade(&mut json)?;
+    }
+    json["version"] = ARCHIVE_VERSION.into();
+    Ok(json)
+}
+
+/// Calls `f` for every chat in the `list` array of an archive
+fn each_chat(
+    json: &mut Value,
+    list: &str,
+    mut f: impl FnMut(&mut Map<String, Value>) -> Result<(), MainError>,
+) -> Result<(), MainError> {
+    let chats = json.get_mut(list).and_then(Value::as_array_mut);
+    for chat in chats.into_iter().flatten() {
+        match chat.as_object_mut() {
+            Some(chat) => f(chat)?,
+            None => Err(BackupError::Invalid(format!("{} are not objects", list)))?,
+        }
+    }
+    Ok(())
+}
+
+/// Version 2 keeps chat titles and keys imported chats by id. Imports keyed by
+/// title can't be matched to chats, they are dropped and have to be imported again
+fn drop_imports_by_title(json: &mut Value) -> Result<(), MainError> {
+    each_chat(json, "chats", |chat| {
+        chat.entry("title").or_insert(Value::Null);
+        Ok(())
+    })?;
+    if let Some(imported) = json.get_mut("imported_chats").and_then(Value::as_array_mut) {
+        if !imported.is_empty() {
+            log::warn!(
+                "dropped {} imported chats keyed by title, import their history again",
+                imported.len()
+            );
+        }
+        imported.clear();
+    }
+    Ok(())
+}
+
+/// Version 3 keeps parsed solutions instead of message texts, messages that
+/// don't parse are dropped, they stay in the archive file
+fn parse_messages(json: &mut Value) -> Result<(), MainError> {
+    for list in &["chats", "imported_chats"] {
+        each_chat(json, list, |chat| {
+            if let Some(messages) = chat.get_mut("messages") {
+                let raw: Vec<RawChatMessage> =
+                    serde_json::from_value(std::mem::replace(messages, Value::Null))?;
+                let mut parsed = Vec::new();
+                for msg in raw {
+                    let id = msg.id;
+                    match msg.parse() {
+                        Ok(msg) => parsed.push(serde_json::to_value(msg)?),
+                        Err(e) => log::warn!("dropped backed up message {}: {}", id, e),
+                    }
+                }
+                *messages = Value::Array(parsed);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Version 4 keeps kata name mappings of every chat
+fn add_kata_mappings(json: &mut Value) -> Result<(), MainError> {
+    each_chat(json, "chats", |chat| {
+        chat.entry("kata_mappings")
+            .or_insert_with(|| Value::Object(Map::new()));
+        Ok(())
+    })
+}
+
+/// Version 5 keeps honor history
+fn add_honor_history(json: &mut Value) -> Result<(), MainError> {
+    if let Some(archive) = json.as_object_mut() {
+        archive
+            .entry("honor_history")
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::message;
+    use crate::db::{TrackedLanguages, UserId};
+    use crate::storage::MemoryStorage;
+
+    fn filled() -> MemoryStorage {
+        let db = MemoryStorage::new();
+        db.add_user(
+            ChatId(-100),
+            CodeUser {
+                username: Some("nick".to_owned()),
+                firstname: "user".to_owned(),
+                telegram_id: UserId(1),
+                codewars_name: "user".to_owned(),
+            },
+        )
+        .unwrap();
+        db.add_message(ChatId(-100), message(1)).unwrap();
+        db.add_message(ChatId(-200), message(2)).unwrap();
+        db.set_tracked_languages(ChatId(-200), TrackedLanguages::Any)
+            .unwrap();
+        db.set_chat_title(ChatId(-100), ChatName("chat".to_owned()))
+            .unwrap();
+        db.set_kata_mapping(
+            ChatId(-100),
+            "robinson crusoe".to_owned(),
+            KataMapping {
+                kata_id: "5899dc03bc95b1bf1b0000ad".to_owned(),
+                manual: true,
+            },
+        )
+        .unwrap();
+        db.add_imported_message(ChatId(-300), message(3)).unwrap();
+        db.set_imported(ChatId(-400), true).unwrap();
+        db.add_honor_snapshot(
+            "user",
+            HonorSnapshot {
+                taken_at: 100,
+                honor: 10,
+                completed: 1,
+            },
+        )
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let archive = export(&filled()).unwrap();
+        let path = std::env::temp_dir().join(format!("backup_{}.json", uuid::Uuid::new_v4()));
+        save(&archive, &path).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let restored = MemoryStorage::new();
+        restore(&restored, &loaded).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(export(&restored).unwrap().chats).unwrap(),
+            serde_json::to_value(archive.chats).unwrap()
+        );
+        assert_eq!(restored.imported_chats().unwrap().len(), 2);
+        assert!(restored.is_chat_imported(ChatId(-400)).unwrap());
+        assert_eq!(
+            restored
+                .get_settings(ChatId(-200))
+                .unwrap()
+                .tracked_languages,
+            TrackedLanguages::Any
+        );
+        assert_eq!(restored.get_honor_history("user", 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn restore_validation_test() {
+        let db = filled();
+        let archive = export(&db).unwrap();
+        match restore(&db, &archive) {
+            Err(MainError::Backup(BackupError::NotEmpty)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let honor_only = MemoryStorage::new();
+        let snapshot = archive.honor_history["user"][0].clone();
+        honor_only.add_honor_snapshot("user", snapshot).unwrap();
+        match restore(&honor_only, &archive) {
+            Err(MainError::Backup(BackupError::NotEmpty)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut duplicated = archive.clone();
+        duplicated.chats.push(duplicated.chats[0].clone());
+        match restore(&MemoryStorage::new(), &duplicated) {
+            Err(MainError::Backup(BackupError::Invalid(_))) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let newer = Archive {
+            version: ARCHIVE_VERSION + 1,
+            ..archive
+        };
+        match restore(&MemoryStorage::new(), &newer) {
+            Err(MainError::Backup(BackupError::UnsupportedVersion { .. })) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn upgrade_test() {
+        let v1 = serde_json::json!({
+            "version": 1,
+            "created_at": 0,
+            "chats": [{
+                "id": -100,
+                "users": [],
+                "messages": [
+                    { "id": 1, "text": "7\nKata\nhttps://pastebin.com/1", "from": 1 },
+                    { "id": 2, "text": "not a solution", "from": 1 },
+                ],
+                "settings": ChatSettings::default(),
+            }],
+            "imported_chats": [{ "name": "chat", "imported": true, "messages": [] }],
+        });
+        let archive: Archive = serde_json::from_value(upgrade(v1).unwrap()).unwrap();
+        assert_eq!(archive.version, ARCHIVE_VERSION);
+        assert_eq!(archive.chats[0].title, None);
+        assert_eq!(
+            archive.chats[0]
+                .messages
+                .iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert!(archive.chats[0].kata_mappings.is_empty());
+        assert!(archive.imported_chats.is_empty());
+        assert!(archive.honor_history.is_empty());
+        assert_eq!(UPGRADES.len() + 1, ARCHIVE_VERSION as usize);
+    }
+}
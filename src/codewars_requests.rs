@@ -1,73 +1,346 @@
 use crate::error::{CodewarsApiError, MainError};
+use crate::rate_limit::TokenBucket;
+use crate::typed_db::TypedDb;
 use reqwest;
-use serde::{Deserialize, Serialize};
+use reqwest::{header, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json;
+use smart_default::SmartDefault;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
-pub async fn get_honor(username: &str) -> Result<i64, MainError> {
-    fn parse(user: CodewarsHonorResponse, username: &str) -> Result<User, MainError> {
-        Ok(match user {
-            CodewarsHonorResponse::Success(user) => Ok(user),
-            CodewarsHonorResponse::Fail { reason, .. } if reason == "not found" => {
-                Err(CodewarsApiError::NotFound(username.to_owned()))
-            }
-            CodewarsHonorResponse::Fail { reason, .. } => panic!("unknown error: {}", reason),
-        }?)
-    }
-    let honor: CodewarsHonorResponse = serde_json::from_str(
-        reqwest::get(&{
-            let url = format!("https://www.codewars.com/api/v1/users/{}", username);
-            log::info!("Request: {}", &url);
-            url
-        })
-        .await?
-        .text()
-        .await?
-        .as_str(),
-    )?;
-
-    Ok(parse(honor, username)?.honor)
-}
-
-pub async fn get_completed(username: &str) -> Result<Vec<CompletedKata>, MainError> {
-    fn url(user: &str, page: i32) -> String {
-        let url = format!(
-            "https://www.codewars.com/api/v1/users/{}/code-challenges/completed?page={}",
-            user, page
-        );
-        log::info!("Request: {}", &url);
-        url
-    }
-    fn parse(pages: CodewarsResponse, username: &str) -> Result<CompletedKatas, MainError> {
-        Ok(match pages {
-            CodewarsResponse::Success(katas) => Ok(katas),
-            CodewarsResponse::Fail { reason, .. } if reason == "not found" => {
-                Err(CodewarsApiError::NotFound(username.to_owned()))
+/// Codewars API endpoints whose responses are cached
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+pub enum Endpoint {
+    User,
+    Completed,
+}
+
+impl Endpoint {
+    /// How long a cached response stays fresh, in seconds
+    fn ttl(self) -> i64 {
+        match self {
+            Endpoint::User => 15 * 60,
+            Endpoint::Completed => 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Clone)]
+struct CacheKey {
+    endpoint: Endpoint,
+    username: String,
+    page: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedResponse {
+    fetched_at: i64,
+    body: String,
+}
+
+/// Successful Codewars API responses persisted in sled
+pub struct ApiCache {
+    responses: TypedDb<CacheKey, CachedResponse>,
+}
+
+impl ApiCache {
+    pub fn new(tree: sled::Tree) -> Self {
+        Self {
+            responses: TypedDb::new(tree),
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Result<Option<String>, MainError> {
+        let now = chrono::Utc::now().timestamp();
+        Ok(self
+            .responses
+            .get(key)?
+            .filter(|cached| now - cached.fetched_at < key.endpoint.ttl())
+            .map(|cached| cached.body))
+    }
+
+    fn insert(&self, key: &CacheKey, body: String) -> Result<(), MainError> {
+        self.responses.insert(
+            key,
+            CachedResponse {
+                fetched_at: chrono::Utc::now().timestamp(),
+                body,
+            },
+        )
+    }
+
+    /// Drops every cached response for a user, returns how many were dropped
+    pub fn invalidate_user(&self, username: &str) -> Result<usize, MainError> {
+        let keys = self
+            .responses
+            .iter()
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut removed = 0;
+        for key in keys.into_iter().filter(|k| k.username == username) {
+            self.responses.remove(&key)?;
+            removed += 1;
+        }
+        log::info!("invalidated {} cached responses for {}", removed, username);
+        Ok(removed)
+    }
+
+    /// Drops every cached response
+    pub fn clear(&self) -> Result<(), MainError> {
+        self.responses.clear()
+    }
+}
+
+pub const DEFAULT_BASE_URL: &str = "https://www.codewars.com/api/v1";
+
+/// Longest wait between retries, whatever `Retry-After` asks for
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Wait asked by a `Retry-After` value, either seconds or an HTTP date,
+/// dates in the past mean no wait
+fn parse_retry_after(value: &str, now: i64) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            Some(Duration::from_secs((date.timestamp() - now).max(0) as u64))
+        }
+    }
+}
+
+#[derive(Debug, Clone, SmartDefault)]
+pub struct CodewarsConfig {
+    #[default(_code = "DEFAULT_BASE_URL.to_owned()")]
+    pub base_url: String,
+    #[default = 4]
+    pub max_concurrent: usize,
+    #[default = 2.]
+    pub requests_per_second: f64,
+    #[default = 5]
+    pub burst: u32,
+    #[default = 4]
+    pub max_retries: u32,
+    #[default(Duration::from_millis(500))]
+    pub initial_backoff: Duration,
+    #[default(Duration::from_secs(20))]
+    pub timeout: Duration,
+}
+
+impl CodewarsConfig {
+    /// Defaults overridden by `CODEWARS_BASE_URL`, `CODEWARS_MAX_CONCURRENT`,
+    /// `CODEWARS_REQUESTS_PER_SECOND`, `CODEWARS_BURST` and `CODEWARS_MAX_RETRIES`,
+    /// limits that aren't above zero are ignored with a warning
+    pub fn from_env() -> Self {
+        fn var<T: FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        fn positive<T: FromStr + PartialOrd + Default + Display + Copy>(
+            name: &str,
+            default: T,
+        ) -> T {
+            let value = var(name, default);
+            if value > T::default() {
+                value
+            } else {
+                log::warn!("{} must be above zero, using {}", name, default);
+                default
             }
-            CodewarsResponse::Fail { reason, .. } => panic!("unknown error: {}", reason),
-        }?)
+        }
+        let default = Self::default();
+        Self {
+            base_url: var("CODEWARS_BASE_URL", default.base_url.clone()),
+            max_concurrent: positive("CODEWARS_MAX_CONCURRENT", default.max_concurrent),
+            requests_per_second: positive(
+                "CODEWARS_REQUESTS_PER_SECOND",
+                default.requests_per_second,
+            ),
+            burst: positive("CODEWARS_BURST", default.burst),
+            max_retries: var("CODEWARS_MAX_RETRIES", default.max_retries),
+            ..default
+        }
+    }
+}
+
+/// Shared Codewars API client: every request goes through its cache,
+/// concurrency limit, rate limit and retries
+pub struct CodewarsClient {
+    http: reqwest::Client,
+    cache: ApiCache,
+    config: CodewarsConfig,
+    concurrency: Semaphore,
+    rate_limit: TokenBucket,
+}
+
+impl CodewarsClient {
+    pub fn new(cache: ApiCache, config: CodewarsConfig) -> Result<Self, MainError> {
+        let http = reqwest::Client::builder().timeout(config.timeout).build()?;
+        Ok(Self::with_http_client(http, cache, config))
     }
 
-    let pages: CodewarsResponse = serde_json::from_str(
-        reqwest::get(&url(username, 0))
-            .await?
-            .text()
-            .await?
-            .as_str(),
-    )?;
-    let mut pages = vec![parse(pages, username)?];
+    pub fn with_http_client(
+        http: reqwest::Client,
+        cache: ApiCache,
+        config: CodewarsConfig,
+    ) -> Self {
+        Self {
+            http,
+            cache,
+            concurrency: Semaphore::new(config.max_concurrent.max(1)),
+            rate_limit: TokenBucket::new(config.burst, config.requests_per_second),
+            config,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.base_url.trim_end_matches('/'), path)
+    }
+
+    pub fn cache(&self) -> &ApiCache {
+        &self.cache
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, key: CacheKey, url: String) -> Result<T, MainError> {
+        if let Some(body) = self.cache.get(&key)? {
+            log::info!("Cached: {}", &url);
+            return parse(StatusCode::OK, body.as_str(), key.username.as_str());
+        }
+        let response = self.send(url.as_str()).await?;
+        let status = response.status();
+        let body = response.text().await?;
+        let parsed = parse(status, body.as_str(), key.username.as_str())?;
+        self.cache.insert(&key, body)?;
+        Ok(parsed)
+    }
+
+    /// Sends a GET request, retrying with exponential backoff on 429, 5xx and timeouts
+    async fn send(&self, url: &str) -> Result<reqwest::Response, MainError> {
+        fn is_retryable(status: StatusCode) -> bool {
+            status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+        fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+            parse_retry_after(
+                response.headers().get(header::RETRY_AFTER)?.to_str().ok()?,
+                chrono::Utc::now().timestamp(),
+            )
+        }
+
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let permit = self.concurrency.acquire().await;
+            self.rate_limit.acquire().await;
+            log::info!("Request: {}", url);
+            let can_retry = attempt < self.config.max_retries;
+            let wait = match self.http.get(url).send().await {
+                Ok(response) if is_retryable(response.status()) && can_retry => {
+                    log::warn!("Response {} for {}", response.status(), url);
+                    retry_after(&response)
+                        .unwrap_or(backoff)
+                        .min(MAX_RETRY_WAIT)
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if e.is_timeout() && can_retry => {
+                    log::warn!("Timeout for {}", url);
+                    backoff
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // other requests may go while this one waits
+            drop(permit);
+            log::info!("Retrying {} in {:?}", url, wait);
+            tokio::time::delay_for(wait).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// Turns a Codewars response into `T` or a typed error describing the failure
+fn parse<T: DeserializeOwned>(
+    status: StatusCode,
+    body: &str,
+    username: &str,
+) -> Result<T, MainError> {
+    fn failure(status: StatusCode, reason: String) -> CodewarsApiError {
+        let status = status.as_u16();
+        match status {
+            429 => CodewarsApiError::RateLimited { status, reason },
+            500..=599 => CodewarsApiError::Server { status, reason },
+            _ => CodewarsApiError::Unknown { status, reason },
+        }
+    }
+
+    Ok(match serde_json::from_str(body) {
+        Ok(CodewarsResponse::Success(value)) if status.is_success() => Ok(value),
+        Ok(CodewarsResponse::Fail { reason, .. }) if reason == "not found" => {
+            Err(CodewarsApiError::NotFound(username.to_owned()))
+        }
+        Ok(CodewarsResponse::Fail { reason, .. }) => Err(failure(status, reason)),
+        Ok(CodewarsResponse::Success(_)) => Err(failure(status, "unexpected payload".to_owned())),
+        Err(e) if status.is_success() => Err(CodewarsApiError::MalformedPayload {
+            status: status.as_u16(),
+            reason: e.to_string(),
+        }),
+        Err(_) => Err(failure(
+            status,
+            status.canonical_reason().unwrap_or("no reason").to_owned(),
+        )),
+    }?)
+}
+
+pub async fn get_honor(client: &CodewarsClient, username: &str) -> Result<i64, MainError> {
+    Ok(get_user(client, username).await?.honor)
+}
+
+pub async fn get_user(client: &CodewarsClient, username: &str) -> Result<User, MainError> {
+    client
+        .fetch(
+            CacheKey {
+                endpoint: Endpoint::User,
+                username: username.to_owned(),
+                page: 0,
+            },
+            client.url(&format!("/users/{}", username)),
+        )
+        .await
+}
+
+pub async fn get_completed(
+    client: &CodewarsClient,
+    username: &str,
+) -> Result<Vec<CompletedKata>, MainError> {
+    async fn fetch_page(
+        client: &CodewarsClient,
+        user: &str,
+        page: i32,
+    ) -> Result<CompletedKatas, MainError> {
+        client
+            .fetch(
+                CacheKey {
+                    endpoint: Endpoint::Completed,
+                    username: user.to_owned(),
+                    page,
+                },
+                client.url(&format!(
+                    "/users/{}/code-challenges/completed?page={}",
+                    user, page
+                )),
+            )
+            .await
+    }
+
+    let mut pages = vec![fetch_page(client, username, 0).await?];
 
     for page in 1..pages.first().unwrap().total_pages {
-        let new = parse(
-            serde_json::from_str(
-                reqwest::get(&url(username, page))
-                    .await?
-                    .text()
-                    .await?
-                    .as_str(),
-            )?,
-            username,
-        )?;
-        pages.push(new)
+        pages.push(fetch_page(client, username, page).await?)
     }
     Ok(pages.into_iter().fold(Vec::new(), |mut acc, mut page| {
         acc.append(&mut page.data);
@@ -77,21 +350,46 @@ pub async fn get_completed(username: &str) -> Result<Vec<CompletedKata>, MainErr
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(untagged)]
-enum CodewarsHonorResponse {
+enum CodewarsResponse<T> {
     Fail { success: bool, reason: String },
-    Success(User),
+    Success(T),
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct User {
-    honor: i64,
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct User {
+    pub username: String,
+    pub name: Option<String>,
+    pub honor: i64,
+    pub clan: Option<String>,
+    #[serde(rename = "leaderboardPosition")]
+    pub leaderboard_position: Option<i64>,
+    pub skills: Option<Vec<String>>,
+    pub ranks: Ranks,
+    #[serde(rename = "codeChallenges")]
+    pub code_challenges: CodeChallenges,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-#[serde(untagged)]
-enum CodewarsResponse {
-    Fail { success: bool, reason: String },
-    Success(CompletedKatas),
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Ranks {
+    pub overall: Rank,
+    pub languages: BTreeMap<String, Rank>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Rank {
+    /// -8 for 8 kyu up to -1 for 1 kyu, 1 and above for dan
+    pub rank: i32,
+    pub name: String,
+    pub color: String,
+    pub score: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CodeChallenges {
+    #[serde(rename = "totalAuthored")]
+    pub total_authored: i64,
+    #[serde(rename = "totalCompleted")]
+    pub total_completed: i64,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -103,7 +401,23 @@ struct CompletedKatas {
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct CompletedKata {
+    pub id: String,
     pub name: String,
+    pub slug: String,
     #[serde(rename = "completedLanguages")]
     pub completed_languages: Vec<String>,
+    #[serde(rename = "completedAt")]
+    pub completed_at: Option<String>,
 }
+
+impl CompletedKata {
+    /// Unix time of the completion
+    pub fn completed_timestamp(&self) -> Option<i64> {
+        chrono::DateTime::parse_from_rfc3339(self.completed_at.as_ref()?)
+            .ok()
+            .map(|d| d.timestamp())
+    }
+}
+
+#[cfg(test)]
+pub mod tests;
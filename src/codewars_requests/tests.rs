@@ -0,0 +1,194 @@
+//! Runs the Codewars client against a local mockito server serving
+//! recorded responses from `fixtures/codewars`.
+
+use super::*;
+use mockito::{mock, Mock};
+
+pub const USER: &str = include_str!("../../fixtures/codewars/user.json");
+pub const COMPLETED_PAGE0: &str = include_str!("../../fixtures/codewars/completed_page0.json");
+pub const COMPLETED_PAGE1: &str = include_str!("../../fixtures/codewars/completed_page1.json");
+pub const NOT_FOUND: &str = include_str!("../../fixtures/codewars/not_found.json");
+
+/// Client pointed at the mock server with a throwaway cache
+pub fn client() -> CodewarsClient {
+    let cache = ApiCache::new(
+        sled::Config::new()
+            .temporary(true)
+            .open()
+            .unwrap()
+            .open_tree("api_cache")
+            .unwrap(),
+    );
+    CodewarsClient::with_http_client(
+        reqwest::Client::new(),
+        cache,
+        CodewarsConfig {
+            base_url: mockito::server_url(),
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(10),
+            ..CodewarsConfig::default()
+        },
+    )
+}
+
+pub fn mock_json(path: &str, status: usize, body: &str) -> Mock {
+    mock("GET", path)
+        .with_status(status)
+        .with_header("content-type", "application/json")
+        .with_body(body)
+        .create()
+}
+
+pub fn mock_user(username: &str) -> Mock {
+    mock_json(&format!("/users/{}", username), 200, USER)
+}
+
+pub fn mock_completed(username: &str) -> Vec<Mock> {
+    vec![
+        mock_json(
+            &format!("/users/{}/code-challenges/completed?page=0", username),
+            200,
+            COMPLETED_PAGE0,
+        ),
+        mock_json(
+            &format!("/users/{}/code-challenges/completed?page=1", username),
+            200,
+            COMPLETED_PAGE1,
+        ),
+    ]
+}
+
+#[tokio::test]
+async fn get_honor_test() {
+    let _m = mock_user("honor_user");
+
+    assert_eq!(get_honor(&client(), "honor_user").await.unwrap(), 544);
+}
+
+#[tokio::test]
+async fn get_user_test() {
+    let _m = mock_user("profile_user");
+
+    let user = get_user(&client(), "profile_user").await.unwrap();
+
+    assert_eq!(user.clan.as_deref(), Some("some clan"));
+    assert_eq!(user.leaderboard_position, Some(134792));
+    assert_eq!(user.ranks.overall.name, "3 kyu");
+    assert_eq!(user.ranks.languages["ruby"].score, 1005);
+    assert_eq!(user.code_challenges.total_completed, 230);
+}
+
+#[tokio::test]
+async fn get_completed_all_pages_test() {
+    let _m = mock_completed("paged_user");
+
+    let katas = get_completed(&client(), "paged_user").await.unwrap();
+
+    assert_eq!(
+        katas.iter().map(|k| k.name.as_str()).collect::<Vec<_>>(),
+        vec![
+            "Multiples of 3 and 5",
+            "Robinson Crusoe",
+            "Replace With Alphabet Position"
+        ]
+    );
+    assert_eq!(katas[2].completed_languages, vec!["scala", "haskell"]);
+    assert_eq!(katas[1].id, "5899dc03bc95b1bf1b0000ad");
+    assert_eq!(katas[1].slug, "robinson-crusoe");
+}
+
+#[tokio::test]
+async fn not_found_test() {
+    let _m = mock_json("/users/missing_user", 404, NOT_FOUND);
+
+    match get_honor(&client(), "missing_user").await {
+        Err(MainError::CodewarsApi(CodewarsApiError::NotFound(name))) => {
+            assert_eq!(name, "missing_user")
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn cached_response_test() {
+    let m = mock_user("cached_user").expect(1);
+    let client = client();
+
+    assert_eq!(get_honor(&client, "cached_user").await.unwrap(), 544);
+    assert_eq!(get_honor(&client, "cached_user").await.unwrap(), 544);
+    m.assert();
+}
+
+#[tokio::test]
+async fn invalidated_response_test() {
+    let m = mock_user("invalidated_user").expect(2);
+    let client = client();
+
+    get_honor(&client, "invalidated_user").await.unwrap();
+    assert_eq!(
+        client.cache().invalidate_user("invalidated_user").unwrap(),
+        1
+    );
+    get_honor(&client, "invalidated_user").await.unwrap();
+    m.assert();
+}
+
+#[tokio::test]
+async fn retry_server_error_test() {
+    let m = mock_json("/users/flaky_user", 503, "").expect(2);
+
+    match get_honor(&client(), "flaky_user").await {
+        Err(MainError::CodewarsApi(CodewarsApiError::Server { status, .. })) => {
+            assert_eq!(status, 503)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    m.assert();
+}
+
+#[test]
+fn retry_after_test() {
+    let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let now = 1445412480;
+    assert_eq!(
+        parse_retry_after(" 120 ", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        parse_retry_after(date, now - 30),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(
+        parse_retry_after(date, now + 30),
+        Some(Duration::from_secs(0))
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+}
+
+#[tokio::test]
+async fn unknown_reason_test() {
+    let _m = mock_json(
+        "/users/banned_user",
+        403,
+        r#"{ "success": false, "reason": "forbidden" }"#,
+    );
+
+    match get_honor(&client(), "banned_user").await {
+        Err(MainError::CodewarsApi(CodewarsApiError::Unknown { status, reason })) => {
+            assert_eq!((status, reason.as_str()), (403, "forbidden"))
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn malformed_payload_test() {
+    let _m = mock_json("/users/garbled_user", 200, r#"{ "honour": 5 }"#);
+
+    match get_honor(&client(), "garbled_user").await {
+        Err(MainError::CodewarsApi(CodewarsApiError::MalformedPayload { status, .. })) => {
+            assert_eq!(status, 200)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
@@ -1,8 +1,18 @@
-use crate::error::MainError;
-use crate::typed_db::TypedDb;
+use crate::error::{MainError, SubmissionError};
+use crate::message_parse::{parse_submission, SolutionSubmission};
+use crate::storage::Storage;
+use crate::typed_db::{transaction2, Json, TypedDb};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use smart_default::SmartDefault;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::identity;
+use std::fmt;
+use std::iter::once;
+use std::path::Path;
+
+mod migrations;
+
+const CACHE_SIZE: u64 = 1024 * 1024 * 256;
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
 pub struct ChatId(pub i64);
@@ -23,143 +33,463 @@ pub struct CodeUser {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
+    pub id: i32,
+    pub submission: SolutionSubmission,
+    pub from: UserId,
+    /// Unix time the message was sent, absent in records stored before dates were tracked
+    #[serde(default)]
+    pub date: Option<i64>,
+}
+
+/// `ChatMessage` as stored before solutions were parsed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawChatMessage {
     pub id: i32,
     pub text: String,
     pub from: UserId,
+    #[serde(default)]
+    pub date: Option<i64>,
+}
+
+impl RawChatMessage {
+    pub fn parse(self) -> Result<ChatMessage, SubmissionError> {
+        Ok(ChatMessage {
+            id: self.id,
+            submission: parse_submission(&self.text)?,
+            from: self.from,
+            date: self.date,
+        })
+    }
+}
+
+/// Honor and completed kata count of a Codewars user at `taken_at` (unix seconds)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HonorSnapshot {
+    pub taken_at: i64,
+    pub honor: i64,
+    pub completed: i64,
+}
+
+/// Languages whose completed katas count as solved in a chat's stats.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, SmartDefault)]
+pub enum TrackedLanguages {
+    Any,
+    #[default]
+    Only(#[default(_code = "once(\"scala\".to_owned()).collect()")] BTreeSet<String>),
+}
+
+impl TrackedLanguages {
+    /// Parses command arguments: `any` or a list of Codewars language ids.
+    pub fn from_args(args: &[&str]) -> Option<Self> {
+        match args {
+            [] => None,
+            [any] if any.eq_ignore_ascii_case("any") => Some(TrackedLanguages::Any),
+            langs => Some(TrackedLanguages::Only(
+                langs.iter().map(|l| l.to_lowercase()).collect(),
+            )),
+        }
+    }
+
+    pub fn tracks_any_of(&self, languages: &[String]) -> bool {
+        match self {
+            TrackedLanguages::Any => !languages.is_empty(),
+            TrackedLanguages::Only(tracked) => languages.iter().any(|l| tracked.contains(l)),
+        }
+    }
+}
+
+impl fmt::Display for TrackedLanguages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackedLanguages::Any => write!(f, "any language"),
+            TrackedLanguages::Only(langs) => {
+                write!(
+                    f,
+                    "{}",
+                    langs.iter().cloned().collect::<Vec<_>>().join(", ")
+                )
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatSettings {
+    #[serde(default)]
+    pub tracked_languages: TrackedLanguages,
+}
+
+/// Codewars kata a posted kata name stands for in a chat
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KataMapping {
+    pub kata_id: String,
+    /// Set with /matchkata, automatic matches never replace it
+    pub manual: bool,
 }
 
 pub struct Persist {
+    db: sled::Db,
+    meta: TypedDb<String, u32, Json>,
     users: TypedDb<ChatId, HashMap<UserId, CodeUser>>,
-    messages: TypedDb<ChatId, Vec<ChatMessage>>,
-    imported_messages: TypedDb<ChatName, Vec<ChatMessage>>,
-    was_chat_imported: TypedDb<ChatName, bool>,
+    messages: TypedDb<(ChatId, i32), ChatMessage>,
+    imported_messages: TypedDb<(ChatId, i32), ChatMessage>,
+    was_chat_imported: TypedDb<ChatId, bool>,
+    chat_titles: TypedDb<ChatId, ChatName>,
+    kata_mappings: TypedDb<(ChatId, String), KataMapping>,
+    settings: TypedDb<ChatId, ChatSettings>,
+    honor_history: TypedDb<(String, i64), HonorSnapshot>,
 }
 
 impl Persist {
-    pub fn new(
-        db: sled::Db,
-        msg_db: sled::Db,
-        imported_messages: sled::Db,
-        was_chat_imported: sled::Db,
-    ) -> Self {
-        Self {
-            users: TypedDb::new(db),
-            messages: TypedDb::new(msg_db),
-            imported_messages: TypedDb::new(imported_messages),
-            was_chat_imported: TypedDb::new(was_chat_imported),
-        }
-    }
-
-    pub fn add_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
-        let mut messages = self.messages.get(&chat_id)?.map_or(Vec::new(), identity);
-        messages.push(msg.clone());
-        self.messages.insert(&chat_id, messages)?;
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, MainError> {
+        Self::new(
+            sled::Config::new()
+                .cache_capacity(CACHE_SIZE)
+                .path(path)
+                .open()?,
+        )
+    }
+
+    pub fn new(db: sled::Db) -> Result<Self, MainError> {
+        Ok(Self {
+            meta: TypedDb::new(db.open_tree("meta")?),
+            users: TypedDb::new(db.open_tree("users")?),
+            messages: TypedDb::new(db.open_tree("messages")?),
+            imported_messages: TypedDb::new(db.open_tree("imported_messages")?),
+            was_chat_imported: TypedDb::new(db.open_tree("imported_chats")?),
+            chat_titles: TypedDb::new(db.open_tree("chat_titles")?),
+            kata_mappings: TypedDb::new(db.open_tree("kata_mappings")?),
+            settings: TypedDb::new(db.open_tree("settings")?),
+            honor_history: TypedDb::new(db.open_tree("honor_history")?),
+            db,
+        })
+    }
+
+    /// Tree of the same database for data not managed by `Persist`
+    pub fn open_tree(&self, name: &str) -> Result<sled::Tree, MainError> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// Brings the database up to the latest schema version, importing
+    /// databases of the old one-directory-per-tree layout from `legacy_dir`
+    pub fn migrate(&self, legacy_dir: &Path) -> Result<(), MainError> {
+        migrations::run(self, legacy_dir)
+    }
+}
+
+impl Storage for Persist {
+    fn add_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
+        self.messages.insert(&(chat_id, msg.id), msg.clone())?;
         log::info!("message {:?} added to chat {:?}", &msg, &chat_id);
         Ok(())
     }
 
-    pub fn add_imported_message(
-        &self,
-        chat_name: ChatName,
-        msg: ChatMessage,
-    ) -> Result<(), MainError> {
-        let mut messages = self
-            .imported_messages
-            .get(&chat_name)?
-            .map_or(Vec::new(), identity);
-        messages.push(msg.clone());
-        self.imported_messages.insert(&chat_name, messages)?;
-        log::info!("imported message {:?} added to chat {:?}", &msg, &chat_name);
+    fn add_imported_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
+        self.imported_messages
+            .insert(&(chat_id, msg.id), msg.clone())?;
+        log::info!("imported message {:?} added to chat {:?}", &msg, &chat_id);
         Ok(())
     }
 
-    pub fn clear_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
-        self.messages.insert(&chat_id, Vec::<ChatMessage>::new())?;
+    fn clear_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.messages.remove_first(&chat_id)?;
         log::info!("messages cleared in chat {:?}", &chat_id);
         Ok(())
     }
 
-    pub fn clear_imported_messages(&self, chat: ChatName) -> Result<(), MainError> {
-        self.imported_messages
-            .insert(&chat, Vec::<ChatMessage>::new())?;
-        log::info!("imported messages cleared in chat {:?}", &chat);
+    fn clear_imported_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.imported_messages.remove_first(&chat_id)?;
+        log::info!("imported messages cleared in chat {:?}", &chat_id);
         Ok(())
     }
 
-    pub fn get_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
-        Ok(self.messages.get(&chat_id)?.map_or(Vec::new(), identity))
+    fn get_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
+        let mut messages = self
+            .messages
+            .scan_first(&chat_id)?
+            .map(|kv| kv.map(|(_, msg)| msg))
+            .collect::<Result<Vec<_>, _>>()?;
+        messages.sort_by_key(|msg| msg.id);
+        Ok(messages)
     }
 
-    //pub fn get_imported_messages(
-    //    &self,
-    //    chat_name: ChatName,
-    //) -> Result<Vec<ChatMessage>, MainError> {
-    //    Ok(self
-    //        .imported_messages
-    //        .get(&chat_name)?
-    //        .map_or(Vec::new(), identity))
-    //}
+    fn get_imported_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
+        let mut messages = self
+            .imported_messages
+            .scan_first(&chat_id)?
+            .map(|kv| kv.map(|(_, msg)| msg))
+            .collect::<Result<Vec<_>, _>>()?;
+        messages.sort_by_key(|msg| msg.id);
+        Ok(messages)
+    }
+
+    fn messages_imported_to_regular(&self, chat_id: ChatId) -> Result<bool, MainError> {
+        let imported = self.get_imported_messages(chat_id)?;
+        let merged = transaction2(
+            &self.messages,
+            &self.was_chat_imported,
+            |messages, was_chat_imported| {
+                if was_chat_imported.get(&chat_id)? == Some(true) {
+                    return Ok(None);
+                }
+                let mut merged = 0;
+                for msg in imported.iter() {
+                    let key = (chat_id, msg.id);
+                    if messages.get(&key)?.is_none() {
+                        messages.insert(&key, msg)?;
+                        merged += 1;
+                    }
+                }
+                was_chat_imported.insert(&chat_id, &true)?;
+                Ok(Some(merged))
+            },
+        )?;
+        if let Some(merged) = merged {
+            log::info!(
+                "merged {} imported messages into chat {:?}",
+                merged,
+                &chat_id
+            );
+        }
+        Ok(merged.is_some())
+    }
 
-    pub fn messages_imported_to_regular(
+    fn is_chat_imported(&self, chat_id: ChatId) -> Result<bool, MainError> {
+        Ok(self
+            .was_chat_imported
+            .get(&chat_id)?
+            .map_or(false, identity))
+    }
+
+    fn set_imported(&self, chat_id: ChatId, imported: bool) -> Result<(), MainError> {
+        self.was_chat_imported.insert(&chat_id, imported)
+    }
+
+    fn set_chat_title(&self, chat_id: ChatId, title: ChatName) -> Result<(), MainError> {
+        self.chat_titles.insert(&chat_id, title)
+    }
+
+    fn chat_title(&self, chat_id: ChatId) -> Result<Option<ChatName>, MainError> {
+        self.chat_titles.get(&chat_id)
+    }
+
+    fn chats_titled(&self, title: &ChatName) -> Result<Vec<ChatId>, MainError> {
+        let mut chats = Vec::new();
+        for entry in self.chat_titles.iter() {
+            let (chat_id, chat_title) = entry?;
+            if &chat_title == title {
+                chats.push(chat_id);
+            }
+        }
+        Ok(chats)
+    }
+
+    fn kata_mappings(&self, chat_id: ChatId) -> Result<BTreeMap<String, KataMapping>, MainError> {
+        self.kata_mappings
+            .scan_first(&chat_id)?
+            .map(|kv| kv.map(|((_, name), mapping)| (name, mapping)))
+            .collect()
+    }
+
+    fn set_kata_mapping(
         &self,
-        chat_name: ChatName,
         chat_id: ChatId,
+        name: String,
+        mapping: KataMapping,
     ) -> Result<(), MainError> {
-        let imported = self.imported_messages.get(&chat_name)?;
-        match imported {
-            Some(v) => self.messages.insert(&chat_id, v)?,
-            None => (),
-        };
-        self.was_chat_imported.insert(&chat_name, true)?;
-        log::info!(
-            "converted imported messages from chat {:?} to chat {:?}",
-            &chat_name,
-            &chat_id
-        );
+        self.kata_mappings.insert(&(chat_id, name), mapping)
+    }
+
+    fn remove_kata_mapping(&self, chat_id: ChatId, name: &str) -> Result<(), MainError> {
+        self.kata_mappings.remove(&(chat_id, name.to_owned()))?;
         Ok(())
     }
 
-    pub fn is_chat_imported(&self, chat_name: ChatName) -> Result<bool, MainError> {
-        Ok(self
-            .was_chat_imported
-            .get(&chat_name)?
-            .map_or(false, identity))
+    fn chats(&self) -> Result<Vec<ChatId>, MainError> {
+        let mut chats = HashSet::new();
+        for entry in self.users.iter() {
+            chats.insert(entry?.0);
+        }
+        for entry in self.messages.iter() {
+            chats.insert((entry?.0).0);
+        }
+        for entry in self.settings.iter() {
+            chats.insert(entry?.0);
+        }
+        for entry in self.chat_titles.iter() {
+            chats.insert(entry?.0);
+        }
+        for entry in self.kata_mappings.iter() {
+            chats.insert((entry?.0).0);
+        }
+        let mut chats: Vec<_> = chats.into_iter().collect();
+        chats.sort_by_key(|c| c.0);
+        Ok(chats)
     }
 
-    pub fn reset_imported(&self, chat_name: ChatName) -> Result<(), MainError> {
-        log::info!("reset is_imported for chat {:?}", chat_name);
-        Ok(self.was_chat_imported.insert(&chat_name, false)?)
+    fn imported_chats(&self) -> Result<Vec<ChatId>, MainError> {
+        let mut chats = HashSet::new();
+        for entry in self.imported_messages.iter() {
+            chats.insert((entry?.0).0);
+        }
+        for entry in self.was_chat_imported.iter() {
+            chats.insert(entry?.0);
+        }
+        let mut chats: Vec<_> = chats.into_iter().collect();
+        chats.sort_by_key(|c| c.0);
+        Ok(chats)
     }
 
-    pub fn add_user(&self, chat_id: ChatId, user: CodeUser) -> Result<(), MainError> {
-        let mut map = match self.users.get(&chat_id)? {
-            None => HashMap::new(),
-            Some(val) => val,
-        };
-        let user1 = user.clone();
-        map.insert(user1.telegram_id, user1);
-        self.users.insert(&chat_id, map)?;
+    fn add_user(&self, chat_id: ChatId, user: CodeUser) -> Result<(), MainError> {
+        self.users.update(&chat_id, |users| {
+            let mut users = users.unwrap_or_default();
+            users.insert(user.telegram_id, user.clone());
+            Some(users)
+        })?;
         log::info!("user {:?} added in chat {:?}", &user, &chat_id);
         Ok(())
     }
 
-    pub fn remove_user(&self, chat_id: ChatId, user_to_remove: UserId) -> Result<(), MainError> {
-        let mut users: HashMap<UserId, CodeUser> =
-            self.users.get(&chat_id)?.map_or(HashMap::new(), identity);
-        users.remove(&user_to_remove);
-        self.users.insert(&chat_id, users)?;
+    fn remove_user(&self, chat_id: ChatId, user_to_remove: UserId) -> Result<(), MainError> {
+        self.users.update(&chat_id, |users| {
+            let mut users = users.unwrap_or_default();
+            users.remove(&user_to_remove);
+            Some(users)
+        })?;
         log::info!("user {:?} removed in chat {:?}", &user_to_remove, &chat_id);
         Ok(())
     }
 
-    pub fn clear_users(&self, chat_id: ChatId) -> Result<(), MainError> {
+    fn clear_users(&self, chat_id: ChatId) -> Result<(), MainError> {
         self.users
             .insert(&chat_id, HashMap::<UserId, CodeUser>::new())?;
         log::info!("users cleared in chat {:?}", &chat_id);
         Ok(())
     }
 
-    pub fn get_users(&self, chat_id: ChatId) -> Result<HashMap<UserId, CodeUser>, MainError> {
+    fn get_users(&self, chat_id: ChatId) -> Result<HashMap<UserId, CodeUser>, MainError> {
         Ok(self.users.get(&chat_id)?.map_or(HashMap::new(), identity))
     }
+
+    fn get_settings(&self, chat_id: ChatId) -> Result<ChatSettings, MainError> {
+        Ok(self.settings.get(&chat_id)?.unwrap_or_default())
+    }
+
+    fn set_settings(&self, chat_id: ChatId, settings: ChatSettings) -> Result<(), MainError> {
+        self.settings.insert(&chat_id, settings)
+    }
+
+    fn set_tracked_languages(
+        &self,
+        chat_id: ChatId,
+        languages: TrackedLanguages,
+    ) -> Result<(), MainError> {
+        let old = self.settings.fetch_and_update(&chat_id, |settings| {
+            Some(ChatSettings {
+                tracked_languages: languages.clone(),
+                ..settings.unwrap_or_default()
+            })
+        })?;
+        log::info!(
+            "tracked languages changed from {} to {} in chat {:?}",
+            old.unwrap_or_default().tracked_languages,
+            &languages,
+            &chat_id
+        );
+        Ok(())
+    }
+
+    fn get_all_users(&self) -> Result<Vec<CodeUser>, MainError> {
+        let mut users = HashMap::new();
+        for chat in self.users.iter() {
+            let (_, chat_users) = chat?;
+            for user in chat_users.into_iter().map(|(_, u)| u) {
+                users.insert(user.codewars_name.clone(), user);
+            }
+        }
+        Ok(users.into_iter().map(|(_, u)| u).collect())
+    }
+
+    fn add_honor_snapshot(
+        &self,
+        codewars_name: &str,
+        snapshot: HonorSnapshot,
+    ) -> Result<(), MainError> {
+        self.honor_history
+            .insert(&(codewars_name.to_owned(), snapshot.taken_at), snapshot)
+    }
+
+    fn get_honor_history(
+        &self,
+        codewars_name: &str,
+        since: i64,
+    ) -> Result<Vec<HonorSnapshot>, MainError> {
+        self.honor_history
+            .scan_first_from(&codewars_name.to_owned(), &since)?
+            .map(|entry| entry.map(|(_, snapshot)| snapshot))
+            .collect()
+    }
+
+    fn honor_history_names(&self) -> Result<Vec<String>, MainError> {
+        let mut names = BTreeSet::new();
+        for entry in self.honor_history.iter() {
+            names.insert((entry?.0).0);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), MainError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    pub fn temporary_persist() -> Persist {
+        Persist::new(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    pub fn message(id: i32) -> ChatMessage {
+        ChatMessage {
+            id,
+            submission: parse_submission(&format!("7\nKata\nhttps://pastebin.com/{}", id)).unwrap(),
+            from: UserId(1),
+            date: None,
+        }
+    }
+
+    #[test]
+    fn concurrent_add_user_test() {
+        let db = Arc::new(temporary_persist());
+        let threads = (0..16)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        let id = t * 100 + i;
+                        db.add_user(
+                            ChatId(-100),
+                            CodeUser {
+                                username: None,
+                                firstname: format!("user{}", id),
+                                telegram_id: UserId(id),
+                                codewars_name: format!("user{}", id),
+                            },
+                        )
+                        .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(db.get_users(ChatId(-100)).unwrap().len(), 16 * 25);
+    }
 }
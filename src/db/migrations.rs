@@ -0,0 +1,344 @@
+//! Ordered upgrades of the on-disk layout, applied at startup
+
+use super::{ChatId, ChatMessage, ChatName, Persist, RawChatMessage};
+use crate::codewars_requests::ApiCache;
+use crate::error::{MainError, StorageError};
+use crate::typed_db::{Binary, Codec, Json, TypedDb};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const SCHEMA_VERSION: &str = "schema_version";
+
+/// Databases that used to live in their own directories and the trees they moved to
+const LEGACY_DATABASES: &[(&str, &str)] = &[
+    ("users", "users"),
+    ("messages", "messages"),
+    ("imported_msgs", "imported_msgs"),
+    ("was_imported", "was_imported"),
+    ("settings", "settings"),
+    ("honor_history", "honor_history"),
+    ("api_cache", "api_cache"),
+];
+
+type Migration = fn(&Persist, &Path) -> Result<(), MainError>;
+
+/// Migration `i` upgrades schema version `i` to `i + 1`, never reorder or remove entries
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("import legacy databases", import_legacy_databases),
+    ("store messages one per key", split_message_lists),
+    ("encode keys and values in binary", encode_binary),
+    ("key imported messages by chat id", drop_imports_by_title),
+    (
+        "store parsed solutions instead of their text",
+        parse_submissions,
+    ),
+    ("date solutions from imported history", date_from_imports),
+];
+
+/// Trees of imports keyed by chat title, replaced in schema version 4
+fn imports_by_title<C: Codec>(
+    db: &Persist,
+) -> Result<
+    (
+        TypedDb<(ChatName, i32), RawChatMessage, C>,
+        TypedDb<ChatName, bool, C>,
+    ),
+    MainError,
+> {
+    Ok((
+        TypedDb::new(db.db.open_tree("imported_msgs")?),
+        TypedDb::new(db.db.open_tree("was_imported")?),
+    ))
+}
+
+/// Stored messages of `tree` that did not parse in schema version 5
+pub fn unparsed(
+    db: &Persist,
+    tree: &str,
+) -> Result<TypedDb<(ChatId, i32), RawChatMessage>, MainError> {
+    Ok(TypedDb::new(db.db.open_tree(format!("unparsed_{}", tree))?))
+}
+
+pub fn run(db: &Persist, legacy_dir: &Path) -> Result<(), MainError> {
+    let found = db.meta.get(&SCHEMA_VERSION.to_owned())?.unwrap_or(0);
+    let supported = MIGRATIONS.len() as u32;
+    if found > supported {
+        Err(StorageError::UnsupportedSchema { found, supported })?
+    }
+    for (version, (name, migration)) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        log::info!("migrating database to version {}: {}", version + 1, name);
+        migration(db, legacy_dir)?;
+        db.meta
+            .insert(&SCHEMA_VERSION.to_owned(), version as u32 + 1)?;
+        db.db.flush()?;
+    }
+    Ok(())
+}
+
+/// Copies every database of the one-directory-per-tree layout into its tree,
+/// then renames the directory so it is not imported again
+fn import_legacy_databases(db: &Persist, legacy_dir: &Path) -> Result<(), MainError> {
+    for (dir, tree) in LEGACY_DATABASES {
+        let path = legacy_dir.join(dir);
+        if !path.is_dir() {
+            continue;
+        }
+        let legacy = sled::Config::new().path(&path).open()?;
+        let tree = db.db.open_tree(tree)?;
+        let mut copied = 0;
+        for kv in legacy.iter() {
+            let (k, v) = kv?;
+            tree.insert(k, v)?;
+            copied += 1;
+        }
+        tree.flush()?;
+        drop(legacy);
+        std::fs::rename(&path, legacy_dir.join(format!("{}.migrated", dir)))?;
+        log::info!(
+            "imported {} entries from legacy database {:?}",
+            copied,
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Moves messages stored as one `Vec` per chat to one entry per message
+fn split_message_lists(db: &Persist, _: &Path) -> Result<(), MainError> {
+    fn split<Chat>(
+        messages: &TypedDb<(Chat, i32), RawChatMessage, Json>,
+    ) -> Result<usize, MainError>
+    where
+        Chat: Serialize + DeserializeOwned + Clone,
+    {
+        let lists = messages.retyped::<Chat, Vec<RawChatMessage>>();
+        let mut migrated = 0;
+        for entry in lists.iter_decodable() {
+            let (chat, list) = entry?;
+            for msg in list {
+                messages.insert(&(chat.clone(), msg.id), msg)?;
+                migrated += 1;
+            }
+            lists.remove(&chat)?;
+        }
+        Ok(migrated)
+    }
+
+    let migrated = split::<ChatId>(&db.messages.with_codec().retyped())?;
+    let migrated_imported = split(&imports_by_title::<Json>(db)?.0)?;
+    log::info!(
+        "migrated {} messages and {} imported messages to per-message storage",
+        migrated,
+        migrated_imported
+    );
+    Ok(())
+}
+
+/// Re-encodes every tree from JSON to `Binary`, cached API responses are dropped
+fn encode_binary(db: &Persist, _: &Path) -> Result<(), MainError> {
+    fn encode<K, V>(tree: &TypedDb<K, V, Binary>) -> Result<usize, MainError>
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+    {
+        // binary keys never parse as JSON, entries already converted
+        // by an interrupted run are skipped
+        let json = tree.with_codec::<Json>();
+        let entries = json.iter_decodable().collect::<Result<Vec<_>, _>>()?;
+        let count = entries.len();
+        for (k, v) in entries {
+            tree.insert(&k, v)?;
+            json.remove(&k)?;
+        }
+        Ok(count)
+    }
+
+    let (imported_messages, was_chat_imported) = imports_by_title(db)?;
+    let encoded = encode(&db.users)?
+        + encode(&db.messages.retyped::<(ChatId, i32), RawChatMessage>())?
+        + encode(&imported_messages)?
+        + encode(&was_chat_imported)?
+        + encode(&db.settings)?
+        + encode(&db.honor_history)?;
+    ApiCache::new(db.db.open_tree("api_cache")?).clear()?;
+    log::info!("encoded {} entries in binary", encoded);
+    Ok(())
+}
+
+/// Titles don't identify chats, imports that were not converted yet are
+/// dropped and have to be imported again from the original export
+fn drop_imports_by_title(db: &Persist, _: &Path) -> Result<(), MainError> {
+    let (imported_messages, was_chat_imported) = imports_by_title::<Binary>(db)?;
+    let mut pending = BTreeSet::new();
+    for entry in imported_messages.iter() {
+        let ((chat, _), _) = entry?;
+        if was_chat_imported.get(&chat)? != Some(true) {
+            pending.insert(chat.0);
+        }
+    }
+    if !pending.is_empty() {
+        log::warn!(
+            "dropped pending imports of chats {:?}, import their export again",
+            pending
+        );
+    }
+    db.db.drop_tree("imported_msgs")?;
+    db.db.drop_tree("was_imported")?;
+    Ok(())
+}
+
+/// Replaces the text of stored solutions with their parsed form, solutions
+/// that don't parse are moved as they are to `unparsed_<tree>`
+fn parse_submissions(db: &Persist, _: &Path) -> Result<(), MainError> {
+    fn parse(
+        messages: &TypedDb<(ChatId, i32), ChatMessage>,
+        unparsed: &TypedDb<(ChatId, i32), RawChatMessage>,
+    ) -> Result<usize, MainError> {
+        // parsed messages have no text, entries converted by an interrupted run are skipped
+        let raw = messages.retyped::<(ChatId, i32), RawChatMessage>();
+        let entries = raw.iter_decodable().collect::<Result<Vec<_>, _>>()?;
+        let count = entries.len();
+        for (key, msg) in entries {
+            match msg.clone().parse() {
+                Ok(msg) => messages.insert(&key, msg)?,
+                Err(e) => {
+                    log::warn!("moved stored message {:?} to the unparsed ones: {}", key, e);
+                    unparsed.insert(&key, msg)?;
+                    raw.remove(&key)?;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    let parsed = parse(&db.messages, &unparsed(db, "messages")?)?
+        + parse(&db.imported_messages, &unparsed(db, "imported_messages")?)?;
+    log::info!("parsed {} stored solutions", parsed);
+    Ok(())
+}
+
+/// Gives solutions stored before dates were kept the date of the same message
+/// in the imported history, the rest stay undated
+fn date_from_imports(db: &Persist, _: &Path) -> Result<(), MainError> {
+    let undated = db
+        .messages
+        .iter()
+        .filter(|kv| kv.as_ref().map_or(true, |(_, msg)| msg.date.is_none()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut dated = 0;
+    for (key, msg) in undated.iter() {
+        if let Some(date) = db.imported_messages.get(key)?.and_then(|m| m.date) {
+            let msg = ChatMessage {
+                date: Some(date),
+                ..msg.clone()
+            };
+            db.messages.insert(key, msg)?;
+            dated += 1;
+        }
+    }
+    log::info!(
+        "dated {} stored solutions from imported history, {} stay undated",
+        dated,
+        undated.len() - dated
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{message, temporary_persist};
+    use crate::db::{ChatId, CodeUser, UserId};
+    use std::collections::HashMap;
+
+    fn legacy_database(dir: &Path, name: &str, key: &impl Serialize, value: &impl Serialize) {
+        let db = sled::Config::new().path(dir.join(name)).open().unwrap();
+        db.insert(
+            serde_json::to_vec(key).unwrap(),
+            serde_json::to_vec(value).unwrap(),
+        )
+        .unwrap();
+        db.flush().unwrap();
+    }
+
+    #[test]
+    fn legacy_layout_test() {
+        let dir = std::env::temp_dir().join(format!("legacy_layout_{}", uuid::Uuid::new_v4()));
+        let user = CodeUser {
+            username: None,
+            firstname: "user".to_owned(),
+            telegram_id: UserId(1),
+            codewars_name: "user".to_owned(),
+        };
+        let users: HashMap<_, _> = vec![(UserId(1), user)].into_iter().collect();
+        legacy_database(&dir, "users", &ChatId(-100), &users);
+        let raw = |id| RawChatMessage {
+            id,
+            text: format!("7\nKata\nhttps://pastebin.com/{}", id),
+            from: UserId(1),
+            date: None,
+        };
+        let unparsable = RawChatMessage {
+            text: "7Robinson Crusoe https://pastebin.com/3".to_owned(),
+            ..raw(3)
+        };
+        legacy_database(
+            &dir,
+            "messages",
+            &ChatId(-100),
+            &vec![raw(2), raw(1), unparsable],
+        );
+
+        let db = temporary_persist();
+        db.migrate(&dir).unwrap();
+        db.add_message(ChatId(-100), message(10)).unwrap();
+
+        let ids: Vec<_> = db
+            .get_messages(ChatId(-100))
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 10]);
+        let kept: Vec<_> = unparsed(&db, "messages")
+            .unwrap()
+            .iter()
+            .map(|kv| kv.unwrap().1.text)
+            .collect();
+        assert_eq!(kept, vec!["7Robinson Crusoe https://pastebin.com/3"]);
+        assert_eq!(db.get_users(ChatId(-100)).unwrap().len(), 1);
+        assert!(dir.join("users.migrated").is_dir());
+        assert!(!dir.join("messages").exists());
+        assert_eq!(
+            db.meta.get(&SCHEMA_VERSION.to_owned()).unwrap(),
+            Some(MIGRATIONS.len() as u32)
+        );
+
+        db.migrate(&dir).unwrap();
+        assert_eq!(db.get_messages(ChatId(-100)).unwrap().len(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn date_from_imports_test() {
+        let db = temporary_persist();
+        db.add_message(ChatId(-100), message(1)).unwrap();
+        db.add_message(ChatId(-100), message(2)).unwrap();
+        let imported = ChatMessage {
+            date: Some(100),
+            ..message(1)
+        };
+        db.add_imported_message(ChatId(-100), imported).unwrap();
+        db.add_imported_message(ChatId(-100), message(2)).unwrap();
+
+        date_from_imports(&db, Path::new("")).unwrap();
+        let dates: Vec<_> = db
+            .get_messages(ChatId(-100))
+            .unwrap()
+            .into_iter()
+            .map(|m| m.date)
+            .collect();
+        assert_eq!(dates, vec![Some(100), None]);
+    }
+}
@@ -4,17 +4,93 @@ use tokio::prelude::*;
 
 #[derive(Error, From, Debug, Display)]
 pub enum MainError {
-    LogFile(io::Error),
+    Io(io::Error),
     LogInit(log::SetLoggerError),
     Sled(sled::Error),
+    Sqlite(rusqlite::Error),
     Serde(serde_json::Error),
+    Cbor(serde_cbor::Error),
+    KeyEncoding(crate::typed_db::key::Error),
     Network(reqwest::Error),
+    Telegram(teloxide::RequestError),
+    Download(teloxide::DownloadError),
     CodewarsApi(CodewarsApiError),
+    Storage(StorageError),
+    Backup(BackupError),
+    Submission(SubmissionError),
+    Usage(UsageError),
 }
 
 #[derive(Debug, Display)]
 pub enum CodewarsApiError {
     NotFound(String),
+    #[display(fmt = "rate limited (HTTP {}): {}", status, reason)]
+    RateLimited {
+        status: u16,
+        reason: String,
+    },
+    #[display(fmt = "server error (HTTP {}): {}", status, reason)]
+    Server {
+        status: u16,
+        reason: String,
+    },
+    #[display(fmt = "malformed payload (HTTP {}): {}", status, reason)]
+    MalformedPayload {
+        status: u16,
+        reason: String,
+    },
+    #[display(fmt = "unknown failure (HTTP {}): {}", status, reason)]
+    Unknown {
+        status: u16,
+        reason: String,
+    },
 }
 
 impl Error for CodewarsApiError {}
+
+#[derive(Debug, Display)]
+pub enum StorageError {
+    #[display(
+        fmt = "database schema version {} is newer than the supported {}",
+        found,
+        supported
+    )]
+    UnsupportedSchema { found: u32, supported: u32 },
+}
+
+impl Error for StorageError {}
+
+#[derive(Debug, Display)]
+pub enum BackupError {
+    #[display(
+        fmt = "archive version {} is not supported, expected {}",
+        found,
+        supported
+    )]
+    UnsupportedVersion { found: u64, supported: u32 },
+    #[display(fmt = "can only restore into an empty database")]
+    NotEmpty,
+    #[display(fmt = "invalid archive: {}", _0)]
+    Invalid(String),
+}
+
+impl Error for BackupError {}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum SubmissionError {
+    #[display(fmt = "no kyu at the start of the message")]
+    NoKyu,
+    #[display(fmt = "no kata name before the solution")]
+    NoKataName,
+    #[display(fmt = "no link to a known solution host or code block")]
+    NoSolution,
+}
+
+impl Error for SubmissionError {}
+
+/// Command line arguments the binary doesn't understand
+#[derive(Debug, Display)]
+#[display(fmt = "unexpected arguments: {}", _0)]
+pub struct UsageError(pub String);
+
+impl Error for UsageError {}
@@ -0,0 +1,378 @@
+//! Import of chat history exported from Telegram Desktop
+
+use crate::db::{ChatId, ChatMessage, ChatName, UserId};
+use crate::error::{MainError, SubmissionError};
+use crate::message_parse::parse_submission_with_code;
+use crate::parsing_types::{ChatData, ExportedData};
+use crate::storage::Storage;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// What `import` does, parsed from the arguments of the `import` command
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Only report what would be imported
+    pub dry_run: bool,
+    /// Only import the chat with this Bot API id
+    pub chat: Option<ChatId>,
+    /// Match exported chats without a recognizable id to known chats by title
+    pub match_titles: bool,
+}
+
+impl ImportOptions {
+    /// Parses `[--dry-run] [--chat <id>] [--match-titles]` in any order
+    pub fn from_args(mut args: &[&str]) -> Option<Self> {
+        let mut options = Self::default();
+        loop {
+            args = match args {
+                [] => return Some(options),
+                ["--dry-run", rest @ ..] => {
+                    options.dry_run = true;
+                    rest
+                }
+                ["--chat", id, rest @ ..] => {
+                    options.chat = Some(ChatId(id.parse().ok()?));
+                    rest
+                }
+                ["--match-titles", rest @ ..] => {
+                    options.match_titles = true;
+                    rest
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Outcome of importing an export, exported chats are named by their titles
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub imported: Vec<ChatImport>,
+    /// Exported chats matched to a known chat by title, see `import`
+    pub matched_by_title: Vec<(String, ChatId)>,
+    /// Exported chats without a usable id and no known chat with their title
+    pub unmatched: Vec<String>,
+    /// Exported chats skipped because their title or id fits several chats
+    pub ambiguous: Vec<(String, Vec<ChatId>)>,
+}
+
+/// Counts of the messages of one exported chat
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChatImport {
+    pub chat_id: ChatId,
+    pub title: String,
+    /// Recognized solutions
+    pub solutions: usize,
+    /// Solutions the chat didn't have yet
+    pub new: usize,
+    /// Messages that are not solutions
+    pub skipped: usize,
+    /// Solutions that don't parse or have no user to credit them to
+    pub malformed: usize,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run {
+            "would import"
+        } else {
+            "imported"
+        };
+        write!(f, "{} {} chats", verb, self.imported.len())?;
+        for chat in self.imported.iter() {
+            write!(
+                f,
+                "\n  {} ({:?}): {} solutions, {} new, {} skipped, {} malformed",
+                chat.chat_id.0, chat.title, chat.solutions, chat.new, chat.skipped, chat.malformed
+            )?;
+        }
+        for (title, chat_id) in self.matched_by_title.iter() {
+            write!(f, "\nmatched {:?} to chat {} by title", title, chat_id.0)?;
+        }
+        for title in self.unmatched.iter() {
+            write!(f, "\nskipped {:?}: unknown chat", title)?;
+        }
+        for (title, chats) in self.ambiguous.iter() {
+            write!(f, "\nskipped {:?}: ambiguous, could be {:?}", title, chats)?;
+        }
+        Ok(())
+    }
+}
+
+fn title(chat: &ChatData) -> String {
+    chat.name
+        .clone()
+        .unwrap_or_else(|| format!("unnamed chat {}", chat.id))
+}
+
+/// Adds solutions of every exported chat to its imported messages, they are
+/// merged into regular messages once the chat is seen again. Messages are
+/// identified by their id, so importing overlapping exports again only adds
+/// what is missing. Chats are identified by their id, see `ImportOptions`
+/// for matching them by title instead.
+pub fn import(
+    db: &dyn Storage,
+    data: &ExportedData,
+    options: &ImportOptions,
+) -> Result<ImportReport, MainError> {
+    let wanted = |chat_id: &ChatId| options.chat.map_or(true, |only| only == *chat_id);
+    let mut report = ImportReport {
+        dry_run: options.dry_run,
+        ..ImportReport::default()
+    };
+    let mut targets: HashMap<ChatId, Vec<&ChatData>> = HashMap::new();
+    for chat in data.chats() {
+        if let Some(id) = chat.bot_api_id() {
+            targets.entry(ChatId(id)).or_default().push(chat);
+            continue;
+        }
+        let known = match (&chat.name, options.match_titles) {
+            (Some(name), true) => db.chats_titled(&ChatName(name.clone()))?,
+            _ => Vec::new(),
+        };
+        match known.as_slice() {
+            [] if options.chat.is_none() => report.unmatched.push(title(chat)),
+            [] => (),
+            [chat_id] => {
+                if wanted(chat_id) {
+                    report.matched_by_title.push((title(chat), *chat_id));
+                }
+                targets.entry(*chat_id).or_default().push(chat);
+            }
+            _ if known.iter().any(wanted) => report.ambiguous.push((title(chat), known.clone())),
+            _ => (),
+        }
+    }
+
+    let mut targets: Vec<_> = targets.into_iter().filter(|(id, _)| wanted(id)).collect();
+    targets.sort_by_key(|(chat_id, _)| chat_id.0);
+    for (chat_id, chats) in targets {
+        let chat = match chats.as_slice() {
+            [chat] => chat,
+            _ => {
+                for chat in chats.iter() {
+                    report.ambiguous.push((title(chat), vec![chat_id]));
+                }
+                continue;
+            }
+        };
+        let known: HashSet<i32> = db
+            .get_messages(chat_id)?
+            .into_iter()
+            .chain(db.get_imported_messages(chat_id)?)
+            .map(|msg| msg.id)
+            .collect();
+        let mut counts = ChatImport {
+            chat_id,
+            title: title(chat),
+            solutions: 0,
+            new: 0,
+            skipped: 0,
+            malformed: 0,
+        };
+        for msg in chat.messages.iter() {
+            let text = match msg.plain_text() {
+                Some(text) if msg.msg_type == "message" => text,
+                _ => {
+                    counts.skipped += 1;
+                    continue;
+                }
+            };
+            let (submission, from) = match (
+                parse_submission_with_code(&text, &msg.code_blocks()),
+                msg.sender(),
+            ) {
+                (Err(SubmissionError::NoKyu), _) | (Err(SubmissionError::NoSolution), _) => {
+                    counts.skipped += 1;
+                    continue;
+                }
+                (Ok(submission), Some(from)) => (submission, from),
+                _ => {
+                    counts.malformed += 1;
+                    continue;
+                }
+            };
+            counts.solutions += 1;
+            if known.contains(&msg.id) {
+                continue;
+            }
+            counts.new += 1;
+            if !options.dry_run {
+                db.add_imported_message(
+                    chat_id,
+                    ChatMessage {
+                        id: msg.id,
+                        from: UserId(from),
+                        submission,
+                        date: msg.timestamp(),
+                    },
+                )?;
+            }
+        }
+        if counts.new > 0 && !options.dry_run {
+            db.reset_imported(chat_id)?;
+        }
+        report.imported.push(counts);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const EXPORT: &str = r#"{"chats": {"list": [
+        {"name": "Supergroup", "type": "private_supergroup", "id": 1234567890, "messages": [
+            {"id": 1, "type": "message", "from_id": 7, "date": "2020-05-01T10:00:00",
+             "text": ["7\nKata\n", {"type": "link", "text": "https://pastebin.com/abc"}]},
+            {"id": 2, "type": "message", "from_id": 7, "text": "hello"},
+            {"id": 3, "type": "service", "text": "7\nKata\nhttps://pastebin.com/def"},
+            {"id": 4, "type": "message", "text": "7\nKata\nhttps://pastebin.com/ghi"}
+        ]},
+        {"name": "Group", "type": "private_group", "id": 42, "messages": []},
+        {"name": "Renamed", "type": "unknown", "id": 5, "messages": []},
+        {"name": "Twice", "type": "unknown", "id": 6, "messages": []},
+        {"name": "Nobody", "type": "unknown", "id": 7, "messages": []}
+    ]}}"#;
+
+    #[test]
+    fn bot_api_id_test() {
+        let data = ExportedData::from_slice(EXPORT.as_bytes()).unwrap();
+        let ids: Vec<_> = data.chats().iter().map(|c| c.bot_api_id()).collect();
+        assert_eq!(ids, vec![Some(-1001234567890), Some(-42), None, None, None]);
+    }
+
+    #[test]
+    fn import_test() {
+        let db = MemoryStorage::new();
+        db.set_chat_title(ChatId(-100), ChatName("Renamed".to_owned()))
+            .unwrap();
+        db.set_chat_title(ChatId(-200), ChatName("Twice".to_owned()))
+            .unwrap();
+        db.set_chat_title(ChatId(-300), ChatName("Twice".to_owned()))
+            .unwrap();
+        let data = ExportedData::from_slice(EXPORT.as_bytes()).unwrap();
+
+        let supergroup = ChatId(-1001234567890);
+        let report = import(&db, &data, &ImportOptions::default()).unwrap();
+        assert_eq!(
+            report.imported,
+            vec![
+                ChatImport {
+                    chat_id: supergroup,
+                    title: "Supergroup".to_owned(),
+                    solutions: 1,
+                    new: 1,
+                    skipped: 2,
+                    malformed: 1,
+                },
+                ChatImport {
+                    chat_id: ChatId(-42),
+                    title: "Group".to_owned(),
+                    solutions: 0,
+                    new: 0,
+                    skipped: 0,
+                    malformed: 0,
+                }
+            ]
+        );
+        assert_eq!(report.unmatched, vec!["Renamed", "Twice", "Nobody"]);
+        let imported = db.get_imported_messages(supergroup).unwrap();
+        assert_eq!(
+            imported[0].submission.link.as_ref().unwrap().url,
+            "https://pastebin.com/abc"
+        );
+        assert_eq!(imported[0].date, Some(1588327200));
+
+        let options = ImportOptions {
+            match_titles: true,
+            ..ImportOptions::default()
+        };
+        let report = import(&db, &data, &options).unwrap();
+        assert_eq!(
+            report.matched_by_title,
+            vec![("Renamed".to_owned(), ChatId(-100))]
+        );
+        assert_eq!(
+            report.ambiguous,
+            vec![("Twice".to_owned(), vec![ChatId(-300), ChatId(-200)])]
+        );
+        assert_eq!(report.unmatched, vec!["Nobody"]);
+    }
+
+    #[test]
+    fn options_test() {
+        assert_eq!(
+            ImportOptions::from_args(&["--chat", "-42", "--dry-run"]),
+            Some(ImportOptions {
+                dry_run: true,
+                chat: Some(ChatId(-42)),
+                match_titles: false,
+            })
+        );
+        assert_eq!(ImportOptions::from_args(&["--chat"]), None);
+        assert_eq!(ImportOptions::from_args(&["--chat", "group"]), None);
+
+        let db = MemoryStorage::new();
+        let data = ExportedData::from_slice(EXPORT.as_bytes()).unwrap();
+        let options = ImportOptions::from_args(&["--dry-run", "--chat", "-1001234567890"]).unwrap();
+        let report = import(&db, &data, &options).unwrap();
+        assert_eq!(report.imported.len(), 1);
+        assert_eq!(report.imported[0].new, 1);
+        assert!(report.unmatched.is_empty());
+        assert!(db.imported_chats().unwrap().is_empty());
+    }
+
+    #[test]
+    fn chat_export_test() {
+        let db = MemoryStorage::new();
+        let json = include_str!("../fixtures/telegram/chat_export_entities.json");
+        let data = ExportedData::from_slice(json.as_bytes()).unwrap();
+        let report = import(&db, &data, &ImportOptions::default()).unwrap();
+        assert_eq!(
+            report.imported,
+            vec![ChatImport {
+                chat_id: ChatId(-1001234567890),
+                title: "Kata club".to_owned(),
+                solutions: 2,
+                new: 2,
+                skipped: 1,
+                malformed: 1,
+            }]
+        );
+        let imported = db.get_imported_messages(ChatId(-1001234567890)).unwrap();
+        assert_eq!(imported[0].from, UserId(222));
+        assert_eq!(imported[0].submission.kata_name, "Directions Reduction");
+        assert_eq!(imported[0].date, Some(1654070400));
+        let code = &imported[1].submission;
+        assert_eq!(code.kata_name, "Even or Odd");
+        assert!(code.link.is_none());
+        assert!(code.code.as_ref().unwrap().starts_with("fn even_or_odd"));
+        assert_eq!(code.language.as_deref(), Some("rust"));
+        assert_eq!(code.notes.as_deref(), Some("first try"));
+    }
+
+    #[test]
+    fn merge_test() {
+        let db = MemoryStorage::new();
+        let supergroup = ChatId(-1001234567890);
+        let data = ExportedData::from_slice(EXPORT.as_bytes()).unwrap();
+        import(&db, &data, &ImportOptions::default()).unwrap();
+        assert!(db.messages_imported_to_regular(supergroup).unwrap());
+        db.add_message(supergroup, crate::db::tests::message(10))
+            .unwrap();
+
+        let report = import(&db, &data, &ImportOptions::default()).unwrap();
+        assert_eq!(report.imported[0].new, 0);
+        assert!(db.is_chat_imported(supergroup).unwrap());
+        let ids: Vec<_> = db
+            .get_messages(supergroup)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![1, 10]);
+    }
+}
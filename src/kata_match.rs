@@ -0,0 +1,343 @@
+//! Resolution of kata names posted in a chat to the Codewars katas users completed
+
+use crate::codewars_requests::CompletedKata;
+use crate::db::KataMapping;
+use crate::message_parse::{normalize_kata_name, SolutionSubmission};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// Edits a fuzzy match may need, in percent of the longer normalized name
+const MAX_DISTANCE_PERCENT: usize = 20;
+
+lazy_static! {
+    static ref KATA_URL: Regex = Regex::new(r"codewars\.com/kata/([\w-]+)").unwrap();
+    static ref KATA_ID: Regex = Regex::new(r"^[0-9a-f]{24}$").unwrap();
+}
+
+/// How a posted kata was resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchKind {
+    /// Mapping set with /matchkata
+    Manual,
+    /// Codewars kata link in the message
+    Link,
+    /// Mapping resolved earlier in the chat
+    Cached,
+    /// Same normalized name
+    Exact,
+    /// Closest normalized name within `MAX_DISTANCE_PERCENT` edits
+    Fuzzy,
+}
+
+/// Kata id of a `codewars.com/kata/<id>` link or of a bare id
+pub fn parse_kata_id(arg: &str) -> Option<String> {
+    let id = KATA_URL
+        .captures(arg)
+        .map_or(arg, |c| c.get(1).unwrap().as_str());
+    Some(id.to_lowercase()).filter(|id| KATA_ID.is_match(id))
+}
+
+/// Id or slug of the first Codewars kata linked from a submission
+fn linked_kata(submission: &SolutionSubmission) -> Option<String> {
+    submission
+        .link
+        .iter()
+        .map(|l| l.url.as_str())
+        .chain(submission.notes.as_deref())
+        .find_map(|text| KATA_URL.captures(text))
+        .map(|c| c[1].to_owned())
+}
+
+/// Levenshtein distance in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The only kata closest to a normalized name, if it is close enough
+fn closest<'k>(name: &str, completed: &'k [CompletedKata]) -> Option<&'k CompletedKata> {
+    let mut best: Option<(usize, usize, &CompletedKata)> = None;
+    let mut tied = false;
+    for kata in completed {
+        let kata_name = normalize_kata_name(&kata.name);
+        let distance = edit_distance(name, &kata_name);
+        let longest = name.chars().count().max(kata_name.chars().count());
+        match best {
+            Some((closest, _, _)) if distance > closest => (),
+            Some((closest, _, found)) if distance == closest => tied |= found.id != kata.id,
+            _ => {
+                best = Some((distance, longest, kata));
+                tied = false;
+            }
+        }
+    }
+    let (distance, longest, kata) = best?;
+    Some(kata).filter(|_| !tied && distance * 100 <= longest * MAX_DISTANCE_PERCENT)
+}
+
+/// Resolves the posts of one chat for each of its users in turn, unambiguous
+/// name matches are remembered in `resolved` so the chat can cache them
+pub struct KataMatcher {
+    mappings: BTreeMap<String, KataMapping>,
+    resolved: BTreeMap<String, KataMapping>,
+}
+
+impl KataMatcher {
+    /// Matcher using the mappings stored for a chat
+    pub fn new(mappings: BTreeMap<String, KataMapping>) -> Self {
+        Self {
+            mappings,
+            resolved: BTreeMap::new(),
+        }
+    }
+
+    /// Kata of `completed` a submission is about, manual mappings come first,
+    /// then links, the same name, cached mappings and close names. Cached
+    /// mappings only count when the user completed their kata.
+    pub fn resolve<'k>(
+        &mut self,
+        submission: &SolutionSubmission,
+        completed: &'k [CompletedKata],
+    ) -> Option<(&'k CompletedKata, MatchKind)> {
+        let name = normalize_kata_name(&submission.kata_name);
+        let by_id = |id: &str| completed.iter().find(|k| k.id == id);
+        match self.mappings.get(&name) {
+            Some(mapping) if mapping.manual => {
+                return by_id(&mapping.kata_id).map(|k| (k, MatchKind::Manual))
+            }
+            _ => (),
+        }
+        if let Some(linked) = linked_kata(submission) {
+            return completed
+                .iter()
+                .find(|k| k.id == linked || k.slug == linked)
+                .map(|k| (k, MatchKind::Link));
+        }
+        let same_name: Vec<_> = completed
+            .iter()
+            .filter(|k| normalize_kata_name(&k.name) == name)
+            .collect();
+        let cached = self
+            .mappings
+            .get(&name)
+            .and_then(|mapping| by_id(&mapping.kata_id));
+        match (same_name.as_slice(), cached) {
+            ([kata], _) => {
+                self.remember(name, kata);
+                Some((*kata, MatchKind::Exact))
+            }
+            (katas, Some(kata)) if katas.is_empty() || katas.iter().any(|k| k.id == kata.id) => {
+                Some((kata, MatchKind::Cached))
+            }
+            // several katas share the name, the first one is taken but not cached
+            ([kata, ..], _) => Some((*kata, MatchKind::Exact)),
+            ([], _) => {
+                let kata = closest(&name, completed)?;
+                self.remember(name, kata);
+                Some((kata, MatchKind::Fuzzy))
+            }
+        }
+    }
+
+    /// Caches a name match unless the chat already has a mapping for the name
+    fn remember(&mut self, name: String, kata: &CompletedKata) {
+        if !self.mappings.contains_key(&name) {
+            let mapping = KataMapping {
+                kata_id: kata.id.clone(),
+                manual: false,
+            };
+            self.mappings.insert(name.clone(), mapping.clone());
+            self.resolved.insert(name, mapping);
+        }
+    }
+
+    /// Mappings found by name since the matcher was made
+    pub fn resolved(&self) -> &BTreeMap<String, KataMapping> {
+        &self.resolved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_parse::parse_submission;
+
+    fn kata(id: &str, name: &str, slug: &str) -> CompletedKata {
+        CompletedKata {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            slug: slug.to_owned(),
+            completed_languages: vec!["scala".to_owned()],
+            completed_at: None,
+        }
+    }
+
+    fn completed() -> Vec<CompletedKata> {
+        vec![
+            kata(
+                "5899dc03bc95b1bf1b0000ad",
+                "Robinson Crusoe",
+                "robinson-crusoe",
+            ),
+            kata(
+                "54b42f9314d9229fd6000d9c",
+                "Replace With Alphabet Position",
+                "replace-with-alphabet-position",
+            ),
+            kata("53da3dbb4a5168369a0000fe", "Even or Odd", "even-or-odd"),
+            kata("57f75cc397d62fc93d000059", "Even or odd?", "even-or-odd-1"),
+        ]
+    }
+
+    fn resolve(matcher: &mut KataMatcher, msg: &str) -> Option<(String, MatchKind)> {
+        let completed = completed();
+        matcher
+            .resolve(&parse_submission(msg).unwrap(), &completed)
+            .map(|(k, kind)| (k.id.clone(), kind))
+    }
+
+    #[test]
+    fn edit_distance_test() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("crusoe", "crusoe"), 0);
+    }
+
+    #[test]
+    fn resolve_test() {
+        let mut matcher = KataMatcher::new(BTreeMap::new());
+        assert_eq!(
+            resolve(&mut matcher, "7 robinson crusoe! https://pastebin.com/a"),
+            Some(("5899dc03bc95b1bf1b0000ad".to_owned(), MatchKind::Exact))
+        );
+        assert_eq!(
+            resolve(
+                &mut matcher,
+                "6 Replace With Alphabet Positon https://pastebin.com/b"
+            ),
+            Some(("54b42f9314d9229fd6000d9c".to_owned(), MatchKind::Fuzzy))
+        );
+        assert_eq!(
+            resolve(&mut matcher, "6 Create Phone Number https://pastebin.com/c"),
+            None
+        );
+        // both "even or odd" katas normalize the same, the first one wins uncached
+        assert_eq!(
+            resolve(&mut matcher, "8 Even or Odd https://pastebin.com/d"),
+            Some(("53da3dbb4a5168369a0000fe".to_owned(), MatchKind::Exact))
+        );
+        assert_eq!(
+            resolve(
+                &mut matcher,
+                "8 Even or Odd https://pastebin.com/d\nkata: https://www.codewars.com/kata/even-or-odd-1"
+            ),
+            Some(("57f75cc397d62fc93d000059".to_owned(), MatchKind::Link))
+        );
+        assert_eq!(
+            matcher.resolved().keys().collect::<Vec<_>>(),
+            vec!["replace with alphabet positon", "robinson crusoe"]
+        );
+        assert_eq!(
+            resolve(
+                &mut matcher,
+                "6 Replace With Alphabet Positon https://pastebin.com/e"
+            ),
+            Some(("54b42f9314d9229fd6000d9c".to_owned(), MatchKind::Cached))
+        );
+    }
+
+    #[test]
+    fn users_test() {
+        let alice = vec![
+            kata("53da3dbb4a5168369a0000fe", "Even or Odd", "even-or-odd"),
+            kata(
+                "54b42f9314d9229fd6000d9c",
+                "Replace With Alphabet Position",
+                "replace-with-alphabet-position",
+            ),
+        ];
+        let bob = vec![
+            kata("57f75cc397d62fc93d000059", "Even or odd?", "even-or-odd-1"),
+            kata(
+                "5a1b2c3d4e5f60718293a4b5",
+                "Replace With Alphabet Positions",
+                "replace-with-alphabet-positions",
+            ),
+        ];
+        let even = parse_submission("8 Even or Odd https://pastebin.com/a").unwrap();
+        let replace =
+            parse_submission("6 Replace With Alphabet Positon https://pastebin.com/b").unwrap();
+        let id = |found: Option<(&CompletedKata, MatchKind)>| {
+            found.map(|(k, kind)| (k.id.clone(), kind))
+        };
+
+        let mut matcher = KataMatcher::new(BTreeMap::new());
+        assert_eq!(
+            id(matcher.resolve(&even, &alice)),
+            Some(("53da3dbb4a5168369a0000fe".to_owned(), MatchKind::Exact))
+        );
+        assert_eq!(
+            id(matcher.resolve(&replace, &alice)),
+            Some(("54b42f9314d9229fd6000d9c".to_owned(), MatchKind::Fuzzy))
+        );
+        // Alice's matches are cached but Bob completed other katas with these names
+        assert_eq!(
+            id(matcher.resolve(&even, &bob)),
+            Some(("57f75cc397d62fc93d000059".to_owned(), MatchKind::Exact))
+        );
+        assert_eq!(
+            id(matcher.resolve(&replace, &bob)),
+            Some(("5a1b2c3d4e5f60718293a4b5".to_owned(), MatchKind::Fuzzy))
+        );
+        assert_eq!(
+            matcher.resolved()["even or odd"].kata_id,
+            "53da3dbb4a5168369a0000fe"
+        );
+        assert_eq!(
+            matcher.resolved()["replace with alphabet positon"].kata_id,
+            "54b42f9314d9229fd6000d9c"
+        );
+    }
+
+    #[test]
+    fn manual_mapping_test() {
+        let mut mappings = BTreeMap::new();
+        mappings.insert(
+            "robinson crusoe".to_owned(),
+            KataMapping {
+                kata_id: "57f75cc397d62fc93d000059".to_owned(),
+                manual: true,
+            },
+        );
+        let mut matcher = KataMatcher::new(mappings);
+        assert_eq!(
+            resolve(
+                &mut matcher,
+                "7 Robinson Crusoe https://www.codewars.com/kata/5899dc03bc95b1bf1b0000ad/solutions/scala"
+            ),
+            Some(("57f75cc397d62fc93d000059".to_owned(), MatchKind::Manual))
+        );
+        assert!(matcher.resolved().is_empty());
+
+        assert_eq!(
+            parse_kata_id("https://www.codewars.com/kata/5899DC03BC95B1BF1B0000AD/train/scala"),
+            Some("5899dc03bc95b1bf1b0000ad".to_owned())
+        );
+        assert_eq!(
+            parse_kata_id("5899dc03bc95b1bf1b0000ad"),
+            Some("5899dc03bc95b1bf1b0000ad".to_owned())
+        );
+        assert_eq!(parse_kata_id("robinson-crusoe"), None);
+    }
+}
@@ -1,21 +1,45 @@
-use crate::db::{ChatId, ChatMessage, ChatName, CodeUser, Persist, UserId};
-use crate::error::{CodewarsApiError, MainError};
-use crate::message_parse::{is_codewars_solution, kata_name_link};
-use crate::parsing_types::{Text, TextData};
-use crate::stats::{compute_honor, compute_stats};
+use crate::codewars_requests::{get_user, ApiCache, CodewarsClient, CodewarsConfig};
+use crate::db::{
+    ChatId, ChatMessage, ChatName, CodeUser, KataMapping, Persist, TrackedLanguages, UserId,
+};
+use crate::error::{CodewarsApiError, MainError, UsageError};
+use crate::import::{ImportOptions, ImportReport};
+use crate::kata_match::{parse_kata_id, KataMatcher};
+use crate::message_parse::{normalize_kata_name, parse_submission_with_code, CodeBlock};
+use crate::parsing_types::ExportedData;
+use crate::stats::{
+    compute_cheaters, compute_honor, compute_honor_history, compute_ranks, compute_stats,
+    take_honor_snapshots, undated_note,
+};
+use crate::storage::{Backend, SqliteStorage, Storage};
+use crate::utils::DateRange;
 use itertools::Itertools;
+use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use teloxide::prelude::*;
-use teloxide::types::{ChatKind, InputFile, MessageKind, ParseMode};
-use teloxide::utils::{command::BotCommand, html::link};
+use teloxide::types::{
+    ChatKind, ChatMemberStatus, Document, InputFile, MessageEntity, MessageEntityKind, MessageKind,
+    ParseMode,
+};
+use teloxide::utils::{
+    command::BotCommand,
+    html::{escape, link},
+};
 
+mod backup;
 mod codewars_requests;
 mod db;
 mod error;
+mod import;
+mod kata_match;
 mod message_parse;
 mod parsing_types;
+mod rate_limit;
+mod reports;
 mod stats;
+mod storage;
 mod typed_db;
 mod utils;
 
@@ -30,14 +54,63 @@ enum Command {
     DeleteMe,
     #[command(description = "clear users")]
     Clear,
-    #[command(description = "show stats")]
+    #[command(description = "show stats (optionally for week, month or 2020-04-01..2020-04-30)")]
     ShowStats,
-    #[command(description = "show solved")]
+    #[command(description = "show solved (optionally for week, month or 2020-04-01..2020-04-30)")]
     ShowSolved,
     #[command(description = "show honor")]
     ShowHonor,
+    #[command(
+        description = "show or set (chat admins only) tracked languages (e.g. /languages haskell ocaml, /languages any)"
+    )]
+    Languages,
+    #[command(description = "show katas solved on Codewars but not posted and vice versa")]
+    Cheaters,
+    #[command(
+        description = "show or set (chat admins only) which Codewars kata a posted name is (e.g. /matchkata <kata link or id> Robinson Crusoe, /matchkata auto Robinson Crusoe)"
+    )]
+    MatchKata,
+    #[command(description = "forget cached Codewars data for users of this chat")]
+    Refresh,
+    #[command(description = "show a Codewars profile (e.g. /profile codewars_name)")]
+    Profile,
+    #[command(description = "show rank scores per language")]
+    Ranks,
+    #[command(description = "show honor over time (e.g. /honorhistory 30)")]
+    HonorHistory,
+    #[command(description = "send a backup of the whole database (bot admins only, in private)")]
+    Backup,
+    #[command(
+        description = "import this chat's history from a Telegram Desktop export sent with this caption or replied to, --match-titles also matches it by title (bot admins only)"
+    )]
+    Import,
 }
 
+const USAGE: &str = "Usage:
+    func_cheater_stats                  run the bot
+    func_cheater_stats export <path>    write a backup archive of the database
+    func_cheater_stats restore <path>   load a backup archive into an empty database
+    func_cheater_stats import <path> [--dry-run] [--chat <id>] [--match-titles]
+        merge solutions from a Telegram Desktop export (result.json) into the database,
+        --chat imports a single chat by its Bot API id, --match-titles matches chats
+        exported without a recognizable id to the only known chat with their title
+
+STORAGE_BACKEND=sqlite keeps chat data in data.sqlite3 instead of data/, which still
+holds the Codewars API cache. To move existing chat data between backends, run
+export with the old STORAGE_BACKEND, then restore the archive with the new one.";
+
+/// Largest file the Bot API lets bots download
+const MAX_DOWNLOAD_SIZE: u32 = 20 * 1024 * 1024;
+
+const MATCH_KATA_HELP: &str =
+    "Expected no arguments, a Codewars kata link or id followed by the posted name, or auto followed by the posted name";
+
+/// Longest period /honorhistory covers
+const MAX_HISTORY_DAYS: i64 = 3650;
+
+const DATE_RANGE_HELP: &str =
+    "Expected no period, week, month or a date range like 2020-04-01..2020-04-30";
+
 #[tokio::main]
 async fn main() -> Result<(), MainError> {
     fern::Dispatch::new()
@@ -55,24 +128,46 @@ async fn main() -> Result<(), MainError> {
         .chain(fern::log_file("logs.log")?)
         .apply()?;
 
-    let cache_size = 1024 * 1024 * 256;
-    let was_imported = sled::Config::new()
-        .cache_capacity(cache_size)
-        .path("was_imported")
-        .open()?;
-    let imported = sled::Config::new()
-        .cache_capacity(cache_size)
-        .path("imported_msgs")
-        .open()?;
-    let messages = sled::Config::new()
-        .cache_capacity(cache_size)
-        .path("messages")
-        .open()?;
-    let db = sled::Config::new()
-        .cache_capacity(cache_size)
-        .path("users")
-        .open()?;
-    let persist = Arc::new(Persist::new(db, messages, imported, was_imported));
+    let sled_db = Persist::open("data")?;
+    sled_db.migrate(Path::new("."))?;
+    let codewars = Arc::new(CodewarsClient::new(
+        ApiCache::new(sled_db.open_tree("api_cache")?),
+        CodewarsConfig::from_env(),
+    )?);
+    // sled is opened whatever the backend, it holds the API cache
+    let persist: Arc<dyn Storage> = match Backend::from_env() {
+        Backend::Sled => Arc::new(sled_db),
+        Backend::Sqlite => Arc::new(SqliteStorage::open("data.sqlite3")?),
+    };
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => (),
+        ["export", path] => {
+            backup::save(&backup::export(persist.as_ref())?, Path::new(path))?;
+            log::info!("exported the database to {}", path);
+            return Ok(());
+        }
+        ["restore", path] => {
+            backup::restore(persist.as_ref(), &backup::load(Path::new(path))?)?;
+            persist.flush()?;
+            return Ok(());
+        }
+        ["import", path, flags @ ..] => {
+            let options = ImportOptions::from_args(flags).ok_or_else(|| usage_error(&args))?;
+            let data = ExportedData::from_slice(&std::fs::read(path)?)?;
+            let report = import::import(persist.as_ref(), &data, &options)?;
+            persist.flush()?;
+            println!("{}", report);
+            return Ok(());
+        }
+        _ => return Err(usage_error(&args)),
+    }
 
     // remove tmp dir
     let tmp = Path::new("tmp/");
@@ -80,122 +175,186 @@ async fn main() -> Result<(), MainError> {
         std::fs::remove_dir_all(tmp).unwrap();
     }
 
-    // import messages
-    let data_path = Path::new("exported_messages.json");
-    if data_path.exists() {
-        use parsing_types::ExportedData;
-        let messages = std::fs::read_to_string(data_path).unwrap();
-        let data: ExportedData = serde_json::from_str(messages.as_str()).unwrap();
-        for chat in data.chats.list.iter() {
-            if let Some(ref chat_name) = chat.name {
-                persist.clear_messages(ChatId(chat.id)).unwrap();
-                persist
-                    .clear_imported_messages(ChatName(chat_name.clone()))
-                    .unwrap();
-                persist.reset_imported(ChatName(chat_name.clone())).unwrap();
-                for msg in chat.messages.iter().filter(|msg| msg.msg_type == "message") {
-                    let msg_text = match msg.text.as_ref().unwrap() {
-                        Text::String(s) => s.clone(),
-                        Text::Links(vec) => vec
-                            .iter()
-                            .map(|t| {
-                                match t {
-                                    TextData::String(s) => s,
-                                    TextData::Typed { text, .. } => text,
-                                }
-                                .clone()
-                            })
-                            .collect::<Vec<_>>()
-                            .join(""),
-                    };
-
-                    if is_codewars_solution(msg_text.as_str()) {
-                        persist
-                            .add_imported_message(
-                                ChatName(chat_name.clone()),
-                                ChatMessage {
-                                    id: msg.id,
-                                    from: UserId(msg.from_id.unwrap()),
-                                    text: msg_text,
-                                },
-                            )
-                            .unwrap();
-                    }
-                }
-            }
-        }
-        std::fs::rename(
-            data_path,
-            format!("used_{}", data_path.file_name().unwrap().to_str().unwrap()),
-        )
-        .unwrap();
-    }
+    tokio::spawn(snapshot_honor(persist.clone(), codewars.clone()));
 
     let token = std::env::var("TELEGRAM_TOKEN")
         .expect("TELEGRAM_TOKEN env variable expected but wasn't found");
     let bot = Bot::new(token);
     Dispatcher::new(bot)
-        .messages_handler(move |rx| handle_messages(rx, persist.clone()))
+        .messages_handler(move |rx| handle_messages(rx, persist.clone(), codewars.clone()))
         .dispatch()
         .await;
 
     Ok(())
 }
 
-async fn store_message(cx: DispatcherHandlerCx<Message>, db: Arc<Persist>) -> ResponseResult<()> {
+/// Whether `user_id` is listed in the comma separated `BOT_ADMINS` env variable
+fn is_bot_admin(user_id: i32) -> bool {
+    std::env::var("BOT_ADMINS").map_or(false, |admins| {
+        admins.split(',').any(|id| id.trim().parse() == Ok(user_id))
+    })
+}
+
+/// Whether a user may change settings shared by the whole chat: bot admins,
+/// chat admins and anyone in a private chat
+async fn can_manage_chat(
+    cx: &DispatcherHandlerCx<Message>,
+    user_id: i32,
+) -> Result<bool, MainError> {
+    if is_bot_admin(user_id) || matches!(cx.update.chat.kind, ChatKind::Private { .. }) {
+        return Ok(true);
+    }
+    let member = cx.bot.get_chat_member(cx.chat_id(), user_id).send().await?;
+    Ok(matches!(
+        member.status,
+        ChatMemberStatus::Creator | ChatMemberStatus::Administrator
+    ))
+}
+
+/// Snapshots honor of all registered users every `HONOR_SNAPSHOT_HOURS`
+/// (6 by default, at most a year)
+async fn snapshot_honor(db: Arc<dyn Storage>, codewars: Arc<CodewarsClient>) {
+    const DEFAULT_HOURS: u64 = 6;
+    const MAX_SECONDS: u64 = 365 * 24 * 60 * 60;
+    let seconds = match std::env::var("HONOR_SNAPSHOT_HOURS") {
+        Ok(hours) => hours
+            .trim()
+            .parse::<NonZeroU64>()
+            .ok()
+            .and_then(|h| h.get().checked_mul(60 * 60))
+            .filter(|s| *s <= MAX_SECONDS)
+            .unwrap_or_else(|| {
+                log::warn!(
+                    "HONOR_SNAPSHOT_HOURS {} is not a number of hours from 1 to a year, using {}",
+                    hours,
+                    DEFAULT_HOURS
+                );
+                DEFAULT_HOURS * 60 * 60
+            }),
+        Err(_) => DEFAULT_HOURS * 60 * 60,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+    loop {
+        interval.tick().await;
+        match take_honor_snapshots(codewars.as_ref(), db.as_ref()).await {
+            Ok(taken) => log::info!("took {} honor snapshots", taken),
+            Err(e) => log::warn!("Error while taking honor snapshots: {}", e),
+        }
+    }
+}
+
+/// Prints the usage for unexpected command line arguments, `main` returns the
+/// error instead of exiting so the storage is still closed properly
+fn usage_error(args: &[String]) -> MainError {
+    eprintln!("{}", USAGE);
+    UsageError(args.join(" ")).into()
+}
+
+/// Imports history of the current chat from an uploaded export and makes it
+/// regular right away, the chat is found by id unless `match_titles` is set
+async fn import_document(
+    cx: &DispatcherHandlerCx<Message>,
+    document: &Document,
+    db: &dyn Storage,
+    match_titles: bool,
+) -> Result<ImportReport, MainError> {
+    let file = cx.bot.get_file(document.file_id.clone()).send().await?;
+    let mut json = Vec::new();
+    cx.bot.download_file(&file.file_path, &mut json).await?;
+    let chat_id = ChatId(cx.chat_id());
+    let options = ImportOptions {
+        chat: Some(chat_id),
+        match_titles,
+        ..ImportOptions::default()
+    };
+    let report = import::import(db, &ExportedData::from_slice(&json)?, &options)?;
+    db.messages_imported_to_regular(chat_id)?;
+    db.flush()?;
+    Ok(report)
+}
+
+/// `pre` and `code` entities of `text`
+fn code_blocks(text: &str, entities: &[MessageEntity]) -> Vec<CodeBlock> {
+    entities
+        .iter()
+        .filter_map(|entity| {
+            let language = match &entity.kind {
+                MessageEntityKind::Pre { language } => language.clone(),
+                MessageEntityKind::Code => None,
+                _ => return None,
+            };
+            CodeBlock::from_utf16(text, entity.offset, entity.length, language)
+        })
+        .collect()
+}
+
+async fn store_message(
+    cx: DispatcherHandlerCx<Message>,
+    db: Arc<dyn Storage>,
+) -> ResponseResult<()> {
     if let (Some(text), Some(from)) = (cx.update.text(), cx.update.from()) {
-        if is_codewars_solution(text) {
-            log::info!("{} ----- is a codewars solution", text);
-            match db.add_message(
-                ChatId(cx.chat_id()),
-                ChatMessage {
-                    from: UserId(from.id),
-                    text: text.to_owned(),
-                    id: cx.update.id,
-                },
-            ) {
-                Ok(_) => (),
-                Err(e) => log::warn!("Error while processing messages: {}", e),
-            }
+        let code = code_blocks(text, cx.update.entities().unwrap_or_default());
+        match parse_submission_with_code(text, &code) {
+            Ok(submission) => {
+                log::info!("{} ----- is a codewars solution", text);
+                match db.add_message(
+                    ChatId(cx.chat_id()),
+                    ChatMessage {
+                        from: UserId(from.id),
+                        submission,
+                        id: cx.update.id,
+                        date: Some(cx.update.date as i64),
+                    },
+                ) {
+                    Ok(_) => (),
+                    Err(e) => log::warn!("Error while processing messages: {}", e),
+                }
 
-        //cx.answer("Registered!").send().await?;
-        } else {
-            log::info!("{} ----- isn't a codewars solution", text);
+                //cx.answer("Registered!").send().await?;
+            }
+            Err(e) => {
+                log::info!("{} ----- isn't a codewars solution: {}", text, e);
+            }
         }
     }
     Ok(())
 }
 
-async fn handle_messages(rx: DispatcherHandlerRx<Message>, db: Arc<Persist>) {
+async fn handle_messages(
+    rx: DispatcherHandlerRx<Message>,
+    db: Arc<dyn Storage>,
+    codewars: Arc<CodewarsClient>,
+) {
     rx.for_each_concurrent(None, |cx| async {
         async {
-            if let Some(text) = cx.update.text() {
-                // import messages for this chat
-                match match cx.update.chat.kind.clone() {
+            // documents carry commands in their captions
+            if let Some(text) = cx.update.text().or_else(|| cx.update.caption()) {
+                let chat_id = ChatId(cx.chat_id());
+                // remember the title, imports without a usable chat id are matched by it
+                if let Some(title) = match cx.update.chat.kind.clone() {
                     ChatKind::NonPrivate {
                         title: Some(title), ..
-                    } => Some(title),
+                    } => Some(ChatName(title)),
                     ChatKind::Private {
                         first_name: Some(first_name),
                         ..
-                    } => Some(first_name),
+                    } => Some(ChatName(first_name)),
                     _ => None,
                 } {
-                    Some(chat_name) => {
-                        if !db.is_chat_imported(ChatName(chat_name.clone()))? {
-                            db.messages_imported_to_regular(
-                                ChatName(chat_name),
-                                ChatId(cx.chat_id()),
-                            )?
-                        }
+                    if db.chat_title(chat_id)?.as_ref() != Some(&title) {
+                        db.set_chat_title(chat_id, title)?;
                     }
-                    None => (),
-                };
+                }
+
+                // import messages for this chat
+                if !db.is_chat_imported(chat_id)? {
+                    db.messages_imported_to_regular(chat_id)?;
+                }
 
                 // handle message
                 if let Some((command, args)) = Command::parse(text, "CodeWarsCheatStats_bot") {
                     // handle commands
-                    answer_command(&cx, command, db.clone(), args)
+                    answer_command(&cx, command, db.clone(), codewars.as_ref(), args)
                         .await
                         .log_on_error()
                         .await;
@@ -216,9 +375,32 @@ async fn handle_messages(rx: DispatcherHandlerRx<Message>, db: Arc<Persist>) {
 async fn answer_command(
     cx: &DispatcherHandlerCx<Message>,
     command: Command,
-    db: Arc<Persist>,
+    db: Arc<dyn Storage>,
+    codewars: &CodewarsClient,
     args: Vec<&str>,
 ) -> ResponseResult<()> {
+    fn error_text(e: &MainError) -> String {
+        match e {
+            MainError::CodewarsApi(CodewarsApiError::NotFound(name)) => {
+                format!("User not found in Codewars API: {}", name)
+            }
+            MainError::CodewarsApi(CodewarsApiError::RateLimited { .. }) => {
+                "Codewars is limiting our requests right now, try again in a minute".to_owned()
+            }
+            MainError::CodewarsApi(CodewarsApiError::Server { status, .. }) => format!(
+                "Codewars is having trouble (HTTP {}), try again later",
+                status
+            ),
+            MainError::CodewarsApi(CodewarsApiError::MalformedPayload { .. }) => {
+                "Codewars sent a response the bot doesn't understand".to_owned()
+            }
+            MainError::CodewarsApi(CodewarsApiError::Unknown { status, reason }) => {
+                format!("Codewars refused the request (HTTP {}): {}", status, reason)
+            }
+            e => format!("Error while getting stats: {}", e),
+        }
+    }
+
     async fn answer_image(
         cx: &DispatcherHandlerCx<Message>,
         img_path: Result<PathBuf, MainError>,
@@ -227,20 +409,25 @@ async fn answer_command(
             Ok(path) => {
                 cx.answer_photo(InputFile::file(path)).send().await?;
             }
-            Err(MainError::CodewarsApi(CodewarsApiError::NotFound(name))) => {
-                cx.answer(format!("User not found in Codewars API: {}", name))
-                    .send()
-                    .await?;
-            }
             Err(e) => {
-                cx.answer(format!("Error while getting stats: {}", e))
-                    .send()
-                    .await?;
+                log::warn!("Error while getting stats: {}", e);
+                cx.answer(error_text(&e)).send().await?;
             }
         };
         Ok(())
     }
 
+    async fn answer_html(cx: &DispatcherHandlerCx<Message>, text: &str) -> ResponseResult<()> {
+        for answer in utils::chunk_with_size(text) {
+            let mut m = cx.answer(answer);
+            if std::env::var("DONT_SEND_HTML").map_or(true, |_| false) {
+                m = m.parse_mode(ParseMode::HTML);
+            }
+            m.disable_web_page_preview(true).send().await?;
+        }
+        Ok(())
+    }
+
     if let MessageKind::Common { ref from, .. } = cx.update.kind {
         if let Some(from) = from {
             match command {
@@ -303,16 +490,35 @@ async fn answer_command(
                     cx.answer(answer_text).send().await?;
                 }
                 Command::ShowStats => {
-                    if let Ok(us) = db.get_users(ChatId(cx.chat_id())) {
-                        if let Ok(msg) = db.get_messages(ChatId(cx.chat_id())) {
-                            answer_image(cx, compute_stats(us, msg).await).await?;
+                    if let Some(range) = DateRange::parse(args.as_slice()) {
+                        if let (Ok(us), Ok(msg), Ok(settings)) = (
+                            db.get_users(ChatId(cx.chat_id())),
+                            db.get_messages(ChatId(cx.chat_id())),
+                            db.get_settings(ChatId(cx.chat_id())),
+                        ) {
+                            let note = undated_note(&msg, &range);
+                            answer_image(
+                                cx,
+                                compute_stats(
+                                    codewars,
+                                    us,
+                                    msg,
+                                    &settings.tracked_languages,
+                                    &range,
+                                )
+                                .await,
+                            )
+                            .await?;
+                            if let Some(note) = note {
+                                cx.answer(note).send().await?;
+                            }
                         } else {
-                            cx.answer("Internal error 1").send().await?;
+                            cx.answer("Couldn't get chat data due to an internal error")
+                                .send()
+                                .await?;
                         }
                     } else {
-                        cx.answer("Couldn't get user data due to an internal error")
-                            .send()
-                            .await?;
+                        cx.answer(DATE_RANGE_HELP).send().await?;
                     };
                 }
                 Command::Clear => {
@@ -323,6 +529,13 @@ async fn answer_command(
                     cx.answer(answer).send().await?;
                 }
                 Command::ShowSolved => {
+                    let range = match DateRange::parse(args.as_slice()) {
+                        Some(range) => range,
+                        None => {
+                            cx.answer(DATE_RANGE_HELP).send().await?;
+                            return Ok(());
+                        }
+                    };
                     let messages = match db.get_messages(ChatId(cx.chat_id())) {
                         Ok(msgs) => msgs,
                         Err(e) => {
@@ -330,12 +543,20 @@ async fn answer_command(
                             Vec::new()
                         }
                     };
+                    let note = undated_note(&messages, &range);
+                    let messages: Vec<ChatMessage> = messages
+                        .into_iter()
+                        .filter(|msg| range.contains(msg.date))
+                        .collect();
                     let answer = if messages.is_empty() {
                         "No solved katas".to_owned()
                     } else {
                         let messages: Vec<_> = messages
                             .into_iter()
-                            .map(|msg| kata_name_link(msg.text.as_str()))
+                            .map(|msg| {
+                                let s = msg.submission;
+                                (format!("{} {}", s.kyu, s.kata_name), s.link.map(|l| l.url))
+                            })
                             .unique()
                             .sorted()
                             .collect();
@@ -344,21 +565,323 @@ async fn answer_command(
                             "The following katas were solved:\n{}",
                             messages
                                 .into_iter()
-                                .map(|m| link(&m.1.replace("\"", ""), &m.0))
+                                .map(|(name, url)| match url {
+                                    Some(url) => link(&url.replace("\"", ""), &name),
+                                    None => format!("{} (code)", escape(&name)),
+                                })
                                 .join("\n")
                         )
                     };
-                    for answer in utils::chunk_with_size(answer.as_str()) {
-                        let mut m = cx.answer(answer);
-                        if std::env::var("DONT_SEND_HTML").map_or(true, |_| false) {
-                            m = m.parse_mode(ParseMode::HTML);
+                    let answer = match note {
+                        Some(note) => format!("{}\n\n{}", answer, escape(&note)),
+                        None => answer,
+                    };
+                    answer_html(cx, answer.as_str()).await?;
+                }
+                Command::ShowHonor => {
+                    if let Ok(us) = db.get_users(ChatId(cx.chat_id())) {
+                        answer_image(cx, compute_honor(codewars, us).await).await?;
+                    } else {
+                        cx.answer("Couldn't get user data due to an internal error")
+                            .send()
+                            .await?;
+                    };
+                }
+                Command::Languages => {
+                    let chat_id = ChatId(cx.chat_id());
+                    let languages = TrackedLanguages::from_args(args.as_slice());
+                    let allowed = match languages {
+                        Some(_) => can_manage_chat(cx, from.id).await.unwrap_or_else(|e| {
+                            log::warn!("Error {} while checking chat admins", e);
+                            false
+                        }),
+                        None => true,
+                    };
+                    let answer = match languages {
+                        Some(_) if !allowed => {
+                            "Only chat admins can change tracked languages".to_owned()
+                        }
+                        Some(languages) => {
+                            let answer = format!("Now tracking {}", &languages);
+                            match db.set_tracked_languages(chat_id, languages) {
+                                Ok(_) => answer,
+                                Err(e) => {
+                                    log::warn!("Error {} while setting tracked languages", e);
+                                    "Couldn't set tracked languages due to an internal error"
+                                        .to_owned()
+                                }
+                            }
+                        }
+                        None => match db.get_settings(chat_id) {
+                            Ok(settings) => {
+                                format!("Tracking {}", settings.tracked_languages)
+                            }
+                            Err(e) => {
+                                log::warn!("Error {} while getting chat settings", e);
+                                "Couldn't get tracked languages due to an internal error".to_owned()
+                            }
+                        },
+                    };
+                    cx.answer(answer).send().await?;
+                }
+                Command::Cheaters => {
+                    let chat_id = ChatId(cx.chat_id());
+                    match (
+                        db.get_users(chat_id),
+                        db.get_messages(chat_id),
+                        db.get_settings(chat_id),
+                        db.kata_mappings(chat_id),
+                    ) {
+                        (Ok(us), Ok(msg), Ok(settings), Ok(mappings)) => {
+                            let mut matcher = KataMatcher::new(mappings);
+                            let found = compute_cheaters(
+                                codewars,
+                                us,
+                                msg,
+                                &settings.tracked_languages,
+                                &mut matcher,
+                            )
+                            .await;
+                            for (name, mapping) in matcher.resolved() {
+                                if let Err(e) =
+                                    db.set_kata_mapping(chat_id, name.clone(), mapping.clone())
+                                {
+                                    log::warn!("Error {} while caching kata matches", e);
+                                }
+                            }
+                            match found {
+                                Ok(found) => {
+                                    answer_html(cx, reports::cheaters(&found).as_str()).await?
+                                }
+                                Err(e) => {
+                                    cx.answer(error_text(&e)).send().await?;
+                                }
+                            }
+                        }
+                        _ => {
+                            cx.answer("Couldn't get chat data due to an internal error")
+                                .send()
+                                .await?;
                         }
-                        m.disable_web_page_preview(true).send().await?;
                     }
                 }
-                Command::ShowHonor => {
+                Command::MatchKata => {
+                    let chat_id = ChatId(cx.chat_id());
+                    let allowed = match args.as_slice() {
+                        [_, _, ..] => can_manage_chat(cx, from.id).await.unwrap_or_else(|e| {
+                            log::warn!("Error {} while checking chat admins", e);
+                            false
+                        }),
+                        _ => true,
+                    };
+                    match args.as_slice() {
+                        [] => match db.kata_mappings(chat_id) {
+                            Ok(mappings) => {
+                                answer_html(cx, reports::kata_mappings(&mappings).as_str()).await?
+                            }
+                            Err(e) => {
+                                log::warn!("Error {} while getting kata matches", e);
+                                cx.answer("Couldn't get kata matches due to an internal error")
+                                    .send()
+                                    .await?;
+                            }
+                        },
+                        [_, name @ ..] if !name.is_empty() && !allowed => {
+                            cx.answer("Only chat admins can change kata matches")
+                                .send()
+                                .await?;
+                        }
+                        [kata, name @ ..] if !name.is_empty() => {
+                            let name = normalize_kata_name(&name.join(" "));
+                            let updated = if kata.eq_ignore_ascii_case("auto") {
+                                db.remove_kata_mapping(chat_id, &name).map(|_| {
+                                    Some(format!("\"{}\" is matched automatically again", name))
+                                })
+                            } else if let Some(kata_id) = parse_kata_id(kata) {
+                                let answer = format!("\"{}\" is now kata {}", name, kata_id);
+                                db.set_kata_mapping(
+                                    chat_id,
+                                    name,
+                                    KataMapping {
+                                        kata_id,
+                                        manual: true,
+                                    },
+                                )
+                                .map(|_| Some(answer))
+                            } else {
+                                Ok(None)
+                            };
+                            let answer = match updated {
+                                Ok(Some(answer)) => answer,
+                                Ok(None) => MATCH_KATA_HELP.to_owned(),
+                                Err(e) => {
+                                    log::warn!("Error {} while setting a kata match", e);
+                                    "Couldn't set the kata match due to an internal error"
+                                        .to_owned()
+                                }
+                            };
+                            cx.answer(answer).send().await?;
+                        }
+                        _ => {
+                            cx.answer(MATCH_KATA_HELP).send().await?;
+                        }
+                    }
+                }
+                Command::Refresh => {
+                    let cache = codewars.cache();
+                    let answer = match db.get_users(ChatId(cx.chat_id())).and_then(|us| {
+                        us.values()
+                            .map(|u| cache.invalidate_user(u.codewars_name.as_str()))
+                            .collect::<Result<Vec<_>, _>>()
+                    }) {
+                        Ok(refreshed) => {
+                            format!("Cached Codewars data dropped for {} users", refreshed.len())
+                        }
+                        Err(e) => {
+                            log::warn!("Error {} while invalidating cache", e);
+                            "Couldn't refresh cached data due to an internal error".to_owned()
+                        }
+                    };
+                    cx.answer(answer).send().await?;
+                }
+                Command::Profile => {
+                    if args.len() == 1 {
+                        match get_user(codewars, args.first().unwrap()).await {
+                            Ok(user) => answer_html(cx, reports::profile(&user).as_str()).await?,
+                            Err(e) => {
+                                cx.answer(error_text(&e)).send().await?;
+                            }
+                        }
+                    } else {
+                        cx.answer("Codewars username wasn't supplied")
+                            .send()
+                            .await?;
+                    }
+                }
+                Command::HonorHistory => {
+                    let days = match args.first().map(|d| d.parse::<i64>()) {
+                        None => Ok(30),
+                        Some(Ok(days)) if days > 0 && days <= MAX_HISTORY_DAYS => Ok(days),
+                        Some(_) => Err(()),
+                    };
+                    match (days, db.get_users(ChatId(cx.chat_id()))) {
+                        (Ok(days), Ok(us)) => {
+                            answer_image(cx, compute_honor_history(db.as_ref(), us, days)).await?
+                        }
+                        (Err(_), _) => {
+                            cx.answer(format!(
+                                "Number of days should be an integer from 1 to {}",
+                                MAX_HISTORY_DAYS
+                            ))
+                            .send()
+                            .await?;
+                        }
+                        (_, Err(_)) => {
+                            cx.answer("Couldn't get user data due to an internal error")
+                                .send()
+                                .await?;
+                        }
+                    }
+                }
+                Command::Backup => {
+                    if !is_bot_admin(from.id) {
+                        cx.answer("Only bot admins can make backups").send().await?;
+                    } else if !matches!(cx.update.chat.kind, ChatKind::Private { .. }) {
+                        cx.answer("Backups are only sent in private chats")
+                            .send()
+                            .await?;
+                    } else {
+                        let path =
+                            PathBuf::from(format!("tmp/backup_{}.json", uuid::Uuid::new_v4()));
+                        let sent = match backup::export(db.as_ref())
+                            .and_then(|a| backup::save(&a, &path))
+                        {
+                            Ok(()) => cx
+                                .answer_document(InputFile::file(path.clone()))
+                                .send()
+                                .await
+                                .map(|_| ()),
+                            Err(e) => {
+                                log::warn!("Error while making a backup: {}", e);
+                                cx.answer(error_text(&e)).send().await.map(|_| ())
+                            }
+                        };
+                        // the archive holds every chat, it shouldn't outlive the upload
+                        if path.exists() {
+                            if let Err(e) = std::fs::remove_file(&path) {
+                                log::warn!("Error while removing a sent backup: {}", e);
+                            }
+                        }
+                        sent?;
+                    }
+                }
+                Command::Import => {
+                    let document = cx
+                        .update
+                        .document()
+                        .or_else(|| cx.update.reply_to_message().and_then(|m| m.document()));
+                    let match_titles = match args.as_slice() {
+                        [] => Some(false),
+                        ["--match-titles"] => Some(true),
+                        _ => None,
+                    };
+                    match (document, match_titles) {
+                        _ if !is_bot_admin(from.id) => {
+                            cx.answer("Only bot admins can import chat history")
+                                .send()
+                                .await?;
+                        }
+                        (_, None) => {
+                            cx.answer("Expected no arguments or --match-titles")
+                                .send()
+                                .await?;
+                        }
+                        (None, _) => {
+                            cx.answer("Send a Telegram Desktop export (result.json) with /import as its caption or reply /import to it")
+                                .send()
+                                .await?;
+                        }
+                        (Some(document), _)
+                            if document.file_size.map_or(false, |s| s > MAX_DOWNLOAD_SIZE) =>
+                        {
+                            cx.answer("The export is too big for the bot to download, use the import command on the server")
+                                .send()
+                                .await?;
+                        }
+                        (Some(document), Some(match_titles)) => {
+                            match import_document(cx, document, db.as_ref(), match_titles).await {
+                                Ok(report)
+                                    if report.imported.is_empty()
+                                        && report.ambiguous.is_empty() =>
+                                {
+                                    let hint = if match_titles {
+                                        ""
+                                    } else {
+                                        ", /import --match-titles also looks for it by title"
+                                    };
+                                    cx.answer(format!(
+                                        "The export has no history of this chat{}",
+                                        hint
+                                    ))
+                                    .send()
+                                    .await?;
+                                }
+                                Ok(report) => {
+                                    cx.answer(report.to_string()).send().await?;
+                                }
+                                Err(e) => {
+                                    log::warn!("Error while importing an uploaded export: {}", e);
+                                    cx.answer(format!("Couldn't import the export: {}", e))
+                                        .send()
+                                        .await?;
+                                }
+                            }
+                        }
+                    }
+                }
+                Command::Ranks => {
                     if let Ok(us) = db.get_users(ChatId(cx.chat_id())) {
-                        answer_image(cx, compute_honor(us).await).await?;
+                        answer_image(cx, compute_ranks(codewars, us).await).await?;
                     } else {
                         cx.answer("Couldn't get user data due to an internal error")
                             .send()
@@ -1,103 +1,445 @@
+use crate::error::SubmissionError;
 use lazy_static::lazy_static;
-use regex;
+use regex::{self, Match, Regex};
+use serde::{Deserialize, Serialize};
+use std::iter::once;
+use std::ops::Range;
+
+/// Sites solutions are linked from, first match wins, `name` is stored with each solution
+const BUILTIN_HOSTS: &[(&str, &str)] = &[
+    ("pastebin", r"https://pastebin\.com/[a-zA-Z\d]*"),
+    ("gist", r"https://gist\.github\.com/[\w-]+(?:/[\da-fA-F]+)?"),
+    (
+        "hastebin",
+        r"https://(?:hastebin\.com|www\.toptal\.com/developers/hastebin)/(?:share/)?[\w.]+",
+    ),
+    ("paste.rs", r"https://paste\.rs/[\w.]+"),
+    (
+        "codewars",
+        r"https://(?:www\.)?codewars\.com/kata/[\da-f]+/solutions[^\s]*",
+    ),
+    ("rust playground", r"https://play\.rust-lang\.org/\?[^\s]*"),
+];
+
+/// Site a solution can be linked from
+pub struct SolutionHost {
+    pub name: String,
+    link: Regex,
+}
+
+/// Solution hosts recognized in messages
+pub struct HostRegistry {
+    hosts: Vec<SolutionHost>,
+}
+
+impl HostRegistry {
+    pub fn builtin() -> Self {
+        Self {
+            hosts: BUILTIN_HOSTS
+                .iter()
+                .map(|(name, link)| SolutionHost {
+                    name: (*name).to_owned(),
+                    link: Regex::new(link).unwrap(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Builtin hosts and the ones in `SOLUTION_HOSTS`, whitespace separated
+    /// `name=link_regex` entries, which replace builtin hosts of the same name
+    pub fn from_env() -> Self {
+        let mut registry = Self::builtin();
+        if let Ok(hosts) = std::env::var("SOLUTION_HOSTS") {
+            for entry in hosts.split_whitespace() {
+                let mut parts = entry.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(link)) => {
+                        if let Err(e) = registry.add(name, link) {
+                            log::warn!("ignoring solution host {}: {}", name, e);
+                        }
+                    }
+                    _ => log::warn!("ignoring solution host {}: expected name=regex", entry),
+                }
+            }
+        }
+        registry
+    }
+
+    pub fn add(&mut self, name: &str, link: &str) -> Result<(), regex::Error> {
+        let host = SolutionHost {
+            name: name.to_owned(),
+            link: Regex::new(link)?,
+        };
+        self.hosts.retain(|h| h.name != name);
+        self.hosts.push(host);
+        Ok(())
+    }
+
+    /// The first solution link in `msg` starting at or after `start` and its host
+    pub fn find<'m>(&self, msg: &'m str, start: usize) -> Option<(&SolutionHost, Match<'m>)> {
+        self.hosts
+            .iter()
+            .filter_map(|host| host.link.find_at(msg, start).map(|link| (host, link)))
+            .min_by_key(|(_, link)| link.start())
+    }
+}
 
 lazy_static! {
-    static ref IS_SOLUTION_REGEX: regex::Regex =
-        regex::Regex::new(r"^\d[\s\S]*?https://pastebin\.com/").unwrap();
-    static ref KATA_KYU: regex::Regex = regex::Regex::new(r"^\d(?:\s*kyu|\s)").unwrap();
-    static ref JUST_LINK: regex::Regex =
-        regex::Regex::new(r"https://pastebin\.com/[a-zA-Z\d]*").unwrap();
-    static ref LINK_AND_EVERYTHING_AFTER: regex::Regex =
-        regex::Regex::new(r"https://pastebin\.com/(.|\s)*").unwrap();
+    static ref HOSTS: HostRegistry = HostRegistry::from_env();
+    static ref KATA_KYU: Regex = Regex::new(r"^\d(?:\s*kyu|\s)").unwrap();
+    static ref LEADING_KYU: Regex = Regex::new(r"^([1-8])(?:\s*kyu)?\b").unwrap();
+    static ref LINK_LANGUAGE: Regex =
+        Regex::new(r"codewars\.com/kata/[\da-f]+/solutions/([\w-]+)").unwrap();
+    static ref HASHTAG: Regex = Regex::new(r"#(\w+)").unwrap();
 }
 
-pub fn is_codewars_solution(msg: &str) -> bool {
-    IS_SOLUTION_REGEX.is_match(msg)
+/// Where a posted solution is, `host` is the name of a `SolutionHost`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SolutionLink {
+    pub host: String,
+    pub url: String,
 }
 
-pub fn kata_name_link(msg: &str) -> (String, String) {
-    if !is_codewars_solution(msg) {
-        panic!("Text {} is not a codewars solution", msg);
+/// A solution message: kyu, kata name and a link or code block, optionally followed by notes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SolutionSubmission {
+    pub kyu: u8,
+    pub kata_name: String,
+    /// Set for linked solutions
+    pub link: Option<SolutionLink>,
+    /// Set for solutions posted as a code block
+    #[serde(default)]
+    pub code: Option<String>,
+    /// Taken from the code block, Codewars and playground links or the first
+    /// `#hashtag` of the notes
+    pub language: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// `pre` or `code` entity of a message, `range` is in bytes of the message text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    pub range: Range<usize>,
+    pub language: Option<String>,
+}
+
+impl CodeBlock {
+    /// Converts Bot API entity offsets, which count UTF-16 code units
+    pub fn from_utf16(
+        text: &str,
+        offset: usize,
+        length: usize,
+        language: Option<String>,
+    ) -> Option<Self> {
+        let mut units = 0;
+        let mut start = None;
+        for (i, c) in text.char_indices().chain(once((text.len(), '\0'))) {
+            if units == offset {
+                start = Some(i);
+            }
+            if units == offset + length {
+                return Some(CodeBlock {
+                    range: start?..i,
+                    language,
+                });
+            }
+            units += c.len_utf16();
+        }
+        None
     }
-    let link = JUST_LINK
-        .find(msg)
-        .expect(format!("Link not found in {}", msg).as_str());
-    let name = LINK_AND_EVERYTHING_AFTER.replace(msg, "");
-    (
-        name.trim().replace("\n", " "),
-        link.as_str().trim().replace("\n", " "),
-    )
+}
+
+pub fn parse_submission(msg: &str) -> Result<SolutionSubmission, SubmissionError> {
+    parse_submission_with_code(msg, &[])
+}
+
+/// Parses a solution whose code may be in one of `code_blocks` instead of a link,
+/// whichever comes first after the kata name is the solution
+pub fn parse_submission_with_code(
+    msg: &str,
+    code_blocks: &[CodeBlock],
+) -> Result<SolutionSubmission, SubmissionError> {
+    let kyu = LEADING_KYU.captures(msg).ok_or(SubmissionError::NoKyu)?;
+    let name_start = kyu.get(0).unwrap().end();
+    // hosts from the environment may match the kyu, only the rest can be a link
+    let link = HOSTS.find(msg, name_start);
+    let block = code_blocks
+        .iter()
+        .filter(|b| b.range.start >= name_start && !msg[b.range.clone()].trim().is_empty())
+        .min_by_key(|b| b.range.start);
+    let code_first = match (&link, block) {
+        (Some((_, link)), Some(block)) => block.range.start < link.start(),
+        (None, Some(_)) => true,
+        (_, None) => false,
+    };
+    let (solution, link, code, code_language) = match (link, block) {
+        (_, Some(block)) if code_first => (
+            block.range.clone(),
+            None,
+            Some(msg[block.range.clone()].trim_matches('\n').to_owned()),
+            block.language.clone(),
+        ),
+        (Some((host, link)), _) => (
+            link.start()..link.end(),
+            Some(SolutionLink {
+                host: host.name.clone(),
+                url: link.as_str().to_owned(),
+            }),
+            None,
+            None,
+        ),
+        _ => return Err(SubmissionError::NoSolution),
+    };
+    let kata_name = msg[name_start..solution.start]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if kata_name.is_empty() {
+        return Err(SubmissionError::NoKataName);
+    }
+    let notes = Some(msg[solution.end..].trim())
+        .filter(|n| !n.is_empty())
+        .map(str::to_owned);
+    let language = code_language
+        .or_else(|| match &link {
+            Some(link) if link.host == "rust playground" => Some("rust".to_owned()),
+            Some(link) => LINK_LANGUAGE.captures(&link.url).map(|c| c[1].to_owned()),
+            None => None,
+        })
+        .or_else(|| {
+            notes
+                .as_ref()
+                .and_then(|n| HASHTAG.captures(n))
+                .map(|c| c[1].to_lowercase())
+        });
+    Ok(SolutionSubmission {
+        kyu: kyu[1].parse().unwrap(),
+        kata_name,
+        link,
+        code,
+        language,
+        notes,
+    })
+}
+
+/// Reduces a kata name to a form comparable between chat posts and Codewars:
+/// no leading kyu, lowercase, only alphanumerics separated by single spaces.
+pub fn normalize_kata_name(name: &str) -> String {
+    KATA_KYU
+        .replace(name.trim(), "")
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn kata_name_link_test1() {
-        let message = "7
-Functions of Integers on Cartesian Plane
-https://pastebin.com/nRkGjfp5";
+    fn pastebin(kyu: u8, kata_name: &str, url: &str) -> SolutionSubmission {
+        SolutionSubmission {
+            kyu,
+            kata_name: kata_name.to_owned(),
+            link: Some(SolutionLink {
+                host: "pastebin".to_owned(),
+                url: url.to_owned(),
+            }),
+            code: None,
+            language: None,
+            notes: None,
+        }
+    }
 
-        assert!(is_codewars_solution(message));
+    #[test]
+    fn parse_submission_test() {
+        let cases = [
+            (
+                "7\nFunctions of Integers on Cartesian Plane\nhttps://pastebin.com/nRkGjfp5",
+                pastebin(
+                    7,
+                    "Functions of Integers on Cartesian Plane",
+                    "https://pastebin.com/nRkGjfp5",
+                ),
+            ),
+            (
+                "7\nRobinson Crusoe\nhttps://pastebin.com/fZHdUbhT",
+                pastebin(7, "Robinson Crusoe", "https://pastebin.com/fZHdUbhT"),
+            ),
+            (
+                "6\nReplace With Alphabet Position\nhttps://pastebin.com/8hPWe1L6",
+                pastebin(
+                    6,
+                    "Replace With Alphabet Position",
+                    "https://pastebin.com/8hPWe1L6",
+                ),
+            ),
+            (
+                "6 kyu Create Phone Number https://pastebin.com/grekUgAs",
+                pastebin(6, "Create Phone Number", "https://pastebin.com/grekUgAs"),
+            ),
+        ];
+        for (message, submission) in cases.iter() {
+            assert_eq!(parse_submission(message).as_ref(), Ok(submission));
+        }
+    }
 
+    #[test]
+    fn submission_details_test() {
+        let submission = parse_submission(
+            "5kyu  Directions\n Reduction\nhttps://pastebin.com/Qwe12345\nrecursive this time #Haskell",
+        )
+        .unwrap();
+        assert_eq!(submission.kyu, 5);
+        assert_eq!(submission.kata_name, "Directions Reduction");
+        assert_eq!(submission.language.as_deref(), Some("haskell"));
         assert_eq!(
-            kata_name_link(message),
-            (
-                "7 Functions of Integers on Cartesian Plane".to_owned(),
-                "https://pastebin.com/nRkGjfp5".to_owned()
-            )
+            submission.notes.as_deref(),
+            Some("recursive this time #Haskell")
+        );
+
+        let submission = parse_submission(
+            "7 Square Every Digit https://www.codewars.com/kata/546e2562b03326a88e000020/solutions/scala/me",
         )
+        .unwrap();
+        assert_eq!(submission.link.unwrap().host, "codewars");
+        assert_eq!(submission.language.as_deref(), Some("scala"));
+
+        let submission =
+            parse_submission("8 Even or Odd https://play.rust-lang.org/?gist=0123abcd").unwrap();
+        assert_eq!(submission.language.as_deref(), Some("rust"));
     }
 
     #[test]
-    fn kata_name_link_test2() {
-        let message = "7
-Robinson Crusoe
-https://pastebin.com/fZHdUbhT";
-
-        assert!(is_codewars_solution(message));
+    fn parse_submission_errors_test() {
+        assert_eq!(
+            parse_submission("Robinson Crusoe https://pastebin.com/fZHdUbhT"),
+            Err(SubmissionError::NoKyu)
+        );
+        assert_eq!(
+            parse_submission("12 Robinson Crusoe https://pastebin.com/fZHdUbhT"),
+            Err(SubmissionError::NoKyu)
+        );
+        assert_eq!(
+            parse_submission("7 Robinson Crusoe"),
+            Err(SubmissionError::NoSolution)
+        );
+        assert_eq!(
+            parse_submission("7\nhttps://pastebin.com/fZHdUbhT"),
+            Err(SubmissionError::NoKataName)
+        );
+    }
 
+    #[test]
+    fn normalize_kata_name_test() {
+        assert_eq!(normalize_kata_name("7 Robinson Crusoe"), "robinson crusoe");
         assert_eq!(
-            kata_name_link(message),
+            normalize_kata_name("6 kyu Replace With Alphabet-Position!"),
+            "replace with alphabet position"
+        );
+        assert_eq!(
+            normalize_kata_name("Replace With Alphabet Position"),
+            normalize_kata_name("6 Replace  with alphabet position")
+        );
+    }
+
+    #[test]
+    fn solution_hosts_test() {
+        let cases = [
             (
-                "7 Robinson Crusoe".to_owned(),
-                "https://pastebin.com/fZHdUbhT".to_owned()
-            )
-        )
+                "pastebin",
+                "https://pastebin.com/fZHdUbhT",
+            ),
+            (
+                "gist",
+                "https://gist.github.com/someone/0123456789abcdef0123456789abcdef",
+            ),
+            ("hastebin", "https://hastebin.com/ebiqaxofez.rs"),
+            (
+                "hastebin",
+                "https://www.toptal.com/developers/hastebin/share/ebiqaxofez",
+            ),
+            ("paste.rs", "https://paste.rs/Zx1.rs"),
+            (
+                "codewars",
+                "https://www.codewars.com/kata/5a3dd29055519e23ec000074/solutions/rust/me/best_practice",
+            ),
+            (
+                "rust playground",
+                "https://play.rust-lang.org/?version=stable&mode=debug&edition=2018&gist=0123abcd",
+            ),
+        ];
+        for (host, url) in cases.iter() {
+            let message = format!("7\nRobinson Crusoe\n{}\nmy notes", url);
+            let submission = parse_submission(&message).unwrap();
+            assert_eq!(submission.kata_name, "Robinson Crusoe");
+            assert_eq!(
+                submission.link,
+                Some(SolutionLink {
+                    host: (*host).to_owned(),
+                    url: (*url).to_owned()
+                })
+            );
+            assert_eq!(submission.notes.as_deref(), Some("my notes"));
+        }
+
+        assert_eq!(
+            parse_submission("Robinson Crusoe https://pastebin.com/fZHdUbhT"),
+            Err(SubmissionError::NoKyu)
+        );
+        assert_eq!(
+            parse_submission("7 Robinson Crusoe https://example.com/fZHdUbhT"),
+            Err(SubmissionError::NoSolution)
+        );
     }
 
     #[test]
-    fn kata_name_link_test3() {
-        let message = "6
-Replace With Alphabet Position
-https://pastebin.com/8hPWe1L6";
+    fn custom_host_test() {
+        let mut registry = HostRegistry::builtin();
+        registry
+            .add("sourcehut", r"https://paste\.sr\.ht/~[\w]+/[\da-f]+")
+            .unwrap();
+        assert!(registry.add("broken", "https://(").is_err());
 
-        assert!(is_codewars_solution(message));
+        let message = "6 Create Phone Number https://paste.sr.ht/~me/0a1b2c";
+        let (host, link) = registry.find(message, 1).unwrap();
+        assert_eq!(host.name, "sourcehut");
+        assert_eq!(link.as_str(), "https://paste.sr.ht/~me/0a1b2c");
+        assert!(HostRegistry::builtin().find(message, 1).is_none());
 
-        assert_eq!(
-            kata_name_link(message),
-            (
-                "6 Replace With Alphabet Position".to_owned(),
-                "https://pastebin.com/8hPWe1L6".to_owned()
-            )
-        )
+        registry.add("numbers", r"\d+").unwrap();
+        let (host, link) = registry.find("6 Kata 42", 1).unwrap();
+        assert_eq!(host.name, "numbers");
+        assert_eq!(link.start(), 7);
     }
 
     #[test]
-    fn kata_name_link_test4() {
-        let message = "6
-Create Phone Number
-https://pastebin.com/grekUgAs";
+    fn code_block_test() {
+        let message = "8 kyu 🦀 Even or Odd\nfn even_or_odd() {}\nhttps://pastebin.com/fZHdUbhT";
+        // the crab takes two UTF-16 units
+        let block = CodeBlock::from_utf16(message, 21, 19, Some("rust".to_owned())).unwrap();
+        assert_eq!(&message[block.range.clone()], "fn even_or_odd() {}");
+        assert_eq!(CodeBlock::from_utf16(message, 21, 100, None), None);
 
-        assert!(is_codewars_solution(message));
+        let submission = parse_submission_with_code(message, &[block.clone()]).unwrap();
+        assert_eq!(submission.kata_name, "🦀 Even or Odd");
+        assert_eq!(submission.code.as_deref(), Some("fn even_or_odd() {}"));
+        assert_eq!(submission.link, None);
+        assert_eq!(submission.language.as_deref(), Some("rust"));
+        assert_eq!(
+            submission.notes.as_deref(),
+            Some("https://pastebin.com/fZHdUbhT")
+        );
 
+        let linked = "8 Even or Odd https://pastebin.com/fZHdUbhT\nfn even_or_odd() {}";
+        let block = CodeBlock::from_utf16(linked, 44, 19, None).unwrap();
+        let submission = parse_submission_with_code(linked, &[block]).unwrap();
+        assert!(submission.link.is_some());
+        assert_eq!(submission.code, None);
         assert_eq!(
-            kata_name_link(message),
-            (
-                "6 Create Phone Number".to_owned(),
-                "https://pastebin.com/grekUgAs".to_owned()
-            )
-        )
+            parse_submission_with_code("8 Even or Odd", &[]),
+            Err(SubmissionError::NoSolution)
+        );
     }
 }
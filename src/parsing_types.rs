@@ -1,8 +1,32 @@
+use crate::message_parse::CodeBlock;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ExportedData {
-    pub chats: ChatsData,
+/// JSON written by Telegram Desktop, either for the whole account or a single chat
+#[derive(Debug)]
+pub enum ExportedData {
+    Account { chats: ChatsData },
+    Chat(ChatData),
+}
+
+impl ExportedData {
+    /// Parses either shape, account exports are told apart by their `chats` field
+    pub fn from_slice(json: &[u8]) -> serde_json::Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(json)?;
+        match json.get("chats") {
+            Some(chats) => Ok(ExportedData::Account {
+                chats: serde_json::from_value(chats.clone())?,
+            }),
+            None => Ok(ExportedData::Chat(serde_json::from_value(json)?)),
+        }
+    }
+
+    pub fn chats(&self) -> &[ChatData] {
+        match self {
+            ExportedData::Account { chats } => &chats.list,
+            ExportedData::Chat(chat) => std::slice::from_ref(chat),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,16 +36,138 @@ pub struct ChatsData {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatData {
     pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub chat_type: Option<String>,
     pub id: i64,
     pub messages: Vec<MessageData>,
 }
+
+impl ChatData {
+    /// Id of the chat as the Bot API reports it. Exports write ids without
+    /// the `-100` prefix of supergroups and channels or the minus of groups.
+    pub fn bot_api_id(&self) -> Option<i64> {
+        if self.id < 0 {
+            return Some(self.id);
+        }
+        match self.chat_type.as_deref()? {
+            "private_supergroup" | "public_supergroup" | "private_channel" | "public_channel" => {
+                Some(-1_000_000_000_000 - self.id)
+            }
+            "private_group" => Some(-self.id),
+            "personal_chat" | "bot_chat" | "saved_messages" => Some(self.id),
+            _ => None,
+        }
+    }
+}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageData {
     pub id: i32,
     #[serde(rename = "type")]
     pub msg_type: String,
     pub text: Option<Text>,
-    pub from_id: Option<i32>,
+    /// Formatting of `text` in newer exports, `text` is then kept for compatibility
+    #[serde(default)]
+    pub text_entities: Vec<TextEntity>,
+    pub from_id: Option<PeerId>,
+    pub date: Option<String>,
+    /// Unix time as a string, written by newer exports
+    pub date_unixtime: Option<String>,
+}
+
+impl MessageData {
+    /// Unix time of the message, `date` carries no time zone so UTC is assumed for it
+    pub fn timestamp(&self) -> Option<i64> {
+        if let Some(time) = self.date_unixtime.as_ref().and_then(|t| t.parse().ok()) {
+            return Some(time);
+        }
+        chrono::NaiveDateTime::parse_from_str(self.date.as_ref()?, "%Y-%m-%dT%H:%M:%S")
+            .ok()
+            .map(|d| d.timestamp())
+    }
+
+    /// Pieces of the text as `(entity type, text, pre language)`, untyped pieces have no type
+    fn segments(&self) -> Option<Vec<(Option<&str>, &str, Option<&str>)>> {
+        if !self.text_entities.is_empty() {
+            return Some(
+                self.text_entities
+                    .iter()
+                    .map(|e| {
+                        (
+                            Some(e.entity_type.as_str()),
+                            e.text.as_str(),
+                            e.language.as_deref(),
+                        )
+                    })
+                    .collect(),
+            );
+        }
+        Some(match self.text.as_ref()? {
+            Text::String(s) => vec![(None, s.as_str(), None)],
+            Text::Links(vec) => vec
+                .iter()
+                .map(|t| match t {
+                    TextData::String(s) => (None, s.as_str(), None),
+                    TextData::Typed {
+                        text_type,
+                        text,
+                        language,
+                    } => (Some(text_type.as_str()), text.as_str(), language.as_deref()),
+                })
+                .collect(),
+        })
+    }
+
+    /// Text with formatting flattened
+    pub fn plain_text(&self) -> Option<String> {
+        Some(
+            self.segments()?
+                .into_iter()
+                .map(|(_, text, _)| text)
+                .collect(),
+        )
+    }
+
+    /// `pre` and `code` pieces of `plain_text`
+    pub fn code_blocks(&self) -> Vec<CodeBlock> {
+        let mut offset = 0;
+        let mut blocks = Vec::new();
+        for (kind, text, language) in self.segments().unwrap_or_default() {
+            if let Some("pre") | Some("code") = kind {
+                blocks.push(CodeBlock {
+                    range: offset..offset + text.len(),
+                    language: language.filter(|l| !l.is_empty()).map(str::to_owned),
+                });
+            }
+            offset += text.len();
+        }
+        blocks
+    }
+
+    /// Telegram id of the user who sent the message
+    pub fn sender(&self) -> Option<i32> {
+        match self.from_id.as_ref()? {
+            PeerId::Number(id) => i32::try_from(*id).ok(),
+            PeerId::Prefixed(id) if id.starts_with("user") => id["user".len()..].parse().ok(),
+            PeerId::Prefixed(_) => None,
+        }
+    }
+}
+
+/// Sender ids are plain numbers in older exports and `user123` or `channel123` in newer ones
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum PeerId {
+    Number(i64),
+    Prefixed(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TextEntity {
+    #[serde(rename = "type")]
+    pub entity_type: String,
+    pub text: String,
+    /// Set on `pre` entities
+    pub language: Option<String>,
 }
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -37,5 +183,77 @@ pub enum TextData {
         #[serde(rename = "type")]
         text_type: String,
         text: String,
+        language: Option<String>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCOUNT: &str = include_str!("../fixtures/telegram/account_export.json");
+    const CHAT: &str = include_str!("../fixtures/telegram/chat_export.json");
+    const CHAT_ENTITIES: &str = include_str!("../fixtures/telegram/chat_export_entities.json");
+
+    fn parse(json: &str) -> ExportedData {
+        ExportedData::from_slice(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn account_export_test() {
+        let data = parse(ACCOUNT);
+        let chats = data.chats();
+        assert_eq!(chats.len(), 2);
+        assert_eq!(chats[0].bot_api_id(), Some(-1001234567890));
+        assert_eq!(chats[1].bot_api_id(), Some(-42));
+        let msg = &chats[0].messages[1];
+        assert_eq!(
+            msg.plain_text().unwrap(),
+            "6 kyu\nMultiples of 3 or 5\nhttps://pastebin.com/ABCdef12"
+        );
+        assert_eq!(msg.sender(), Some(111));
+        assert_eq!(msg.timestamp(), Some(1588327200));
+    }
+
+    #[test]
+    fn chat_export_test() {
+        let data = parse(CHAT);
+        let chats = data.chats();
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].name.as_deref(), Some("Kata club"));
+        assert_eq!(chats[0].bot_api_id(), Some(-1001234567890));
+        assert_eq!(
+            chats[0].messages[0].plain_text().unwrap(),
+            "7 kyu\nSum of two lowest\nhttps://pastebin.com/XYZ98765"
+        );
+        assert_eq!(chats[0].messages[0].sender(), Some(111));
+    }
+
+    #[test]
+    fn entities_export_test() {
+        let data = parse(CHAT_ENTITIES);
+        let messages = &data.chats()[0].messages;
+        assert_eq!(
+            messages[0].plain_text().unwrap(),
+            "5 kyu\nDirections Reduction\nhttps://pastebin.com/Qwe12345"
+        );
+        assert_eq!(messages[0].sender(), Some(222));
+        assert_eq!(messages[0].timestamp(), Some(1654070400));
+        // channel posts have no user to credit
+        assert_eq!(messages[1].sender(), None);
+        assert_eq!(messages[2].plain_text().unwrap(), "");
+        assert!(messages[2].code_blocks().is_empty());
+        let text = messages[3].plain_text().unwrap();
+        let blocks = messages[3].code_blocks();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].language.as_deref(), Some("rust"));
+        assert!(text[blocks[0].range.clone()].starts_with("fn even_or_odd"));
+        assert!(text[blocks[0].range.end..].starts_with("\nfirst try"));
+    }
+
+    #[test]
+    fn malformed_export_test() {
+        assert!(ExportedData::from_slice(b"{\"chats\": {}}").is_err());
+        assert!(ExportedData::from_slice(b"{\"name\": \"chat\"}").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket: holds up to `capacity` tokens, refilled at `per_second`
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// A bucket always holds at least one token, `per_second` has to be above zero
+    pub fn new(capacity: u32, per_second: f64) -> Self {
+        assert!(
+            per_second > 0.,
+            "token bucket refill rate must be above zero"
+        );
+        let capacity = capacity.max(1) as f64;
+        Self {
+            capacity,
+            per_second,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Waits until a token is available and takes it
+    pub async fn acquire(&self) {
+        while let Some(wait) = self.try_take(Instant::now()) {
+            tokio::time::delay_for(wait).await;
+        }
+    }
+
+    /// Takes a token if there is one, otherwise returns how long to wait for it
+    fn try_take(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (ref mut tokens, ref mut refilled_at) = *state;
+        if now > *refilled_at {
+            let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+            *tokens = self.capacity.min(*tokens + elapsed * self.per_second);
+            *refilled_at = now;
+        }
+        if *tokens >= 1. {
+            *tokens -= 1.;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1. - *tokens) / self.per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_test() {
+        let bucket = TokenBucket::new(2, 4.);
+        let start = Instant::now();
+
+        assert_eq!(bucket.try_take(start), None);
+        assert_eq!(bucket.try_take(start), None);
+        assert_eq!(bucket.try_take(start), Some(Duration::from_millis(250)));
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.try_take(later), None);
+        assert!(bucket.try_take(later).is_some());
+
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.try_take(much_later), None);
+        assert_eq!(bucket.try_take(much_later), None);
+        assert!(bucket.try_take(much_later).is_some());
+
+        let empty = TokenBucket::new(0, 1.);
+        assert_eq!(empty.try_take(start), None);
+        assert_eq!(empty.try_take(start), Some(Duration::from_secs(1)));
+    }
+}
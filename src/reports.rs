@@ -0,0 +1,124 @@
+use crate::codewars_requests::{Rank, User};
+use crate::db::KataMapping;
+use crate::stats::CheaterReport;
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use teloxide::utils::html::{bold, escape, link};
+
+pub fn cheaters(reports: &[CheaterReport]) -> String {
+    if reports.is_empty() {
+        return "No users registered in this chat".to_owned();
+    }
+    reports
+        .iter()
+        .map(|report| {
+            let mut lines = vec![bold(&escape(&report.user.firstname))];
+            if report.is_clean() {
+                lines.push("Everything is posted".to_owned());
+            }
+            if !report.not_posted.is_empty() {
+                lines.push("Solved on Codewars but not posted:".to_owned());
+                lines.extend(report.not_posted.iter().map(|k| format!("- {}", escape(k))));
+            }
+            if !report.not_completed.is_empty() {
+                lines.push("Posted but not solved on Codewars:".to_owned());
+                lines.extend(
+                    report
+                        .not_completed
+                        .iter()
+                        .map(|k| format!("- {}", escape(k))),
+                );
+            }
+            lines.join("\n")
+        })
+        .join("\n\n")
+}
+
+pub fn kata_mappings(mappings: &BTreeMap<String, KataMapping>) -> String {
+    if mappings.is_empty() {
+        return "No posted kata names are matched to Codewars katas yet".to_owned();
+    }
+    mappings
+        .iter()
+        .map(|(name, mapping)| {
+            format!(
+                "{}: {}{}",
+                escape(name),
+                link(
+                    &format!("https://www.codewars.com/kata/{}", mapping.kata_id),
+                    &mapping.kata_id
+                ),
+                if mapping.manual { " (manual)" } else { "" }
+            )
+        })
+        .join("\n")
+}
+
+/// Link to a Codewars profile, `link` escapes the text but a quote in the
+/// username would still end the `href`, so the username is percent-encoded
+fn profile_url(username: &str) -> String {
+    let mut url = "https://www.codewars.com/users/".to_owned();
+    for b in username.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                url.push(b as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    url
+}
+
+pub fn profile(user: &User) -> String {
+    fn rank(rank: &Rank) -> String {
+        format!("{} ({} points)", escape(&rank.name), rank.score)
+    }
+
+    let mut lines = vec![bold(&link(&profile_url(&user.username), &user.username))];
+    if let Some(name) = user.name.as_ref().filter(|n| !n.is_empty()) {
+        lines.push(escape(name));
+    }
+    if let Some(clan) = user.clan.as_ref().filter(|c| !c.is_empty()) {
+        lines.push(format!("Clan: {}", escape(clan)));
+    }
+    lines.push(format!("Honor: {}", user.honor));
+    if let Some(position) = user.leaderboard_position {
+        lines.push(format!("Leaderboard position: #{}", position));
+    }
+    lines.push(format!("Overall rank: {}", rank(&user.ranks.overall)));
+    lines.push(format!(
+        "Katas completed: {}, authored: {}",
+        user.code_challenges.total_completed, user.code_challenges.total_authored
+    ));
+    if !user.ranks.languages.is_empty() {
+        lines.push(bold("Languages"));
+        lines.extend(
+            user.ranks
+                .languages
+                .iter()
+                .sorted_by_key(|(_, r)| -r.score)
+                .map(|(lang, r)| format!("{}: {}", escape(lang), rank(r))),
+        );
+    }
+    if let Some(skills) = user.skills.as_ref().filter(|s| !s.is_empty()) {
+        lines.push(format!("Skills: {}", escape(&skills.join(", "))));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_url_test() {
+        assert_eq!(
+            profile_url("some_user-1"),
+            "https://www.codewars.com/users/some_user-1"
+        );
+        assert_eq!(
+            link(&profile_url("a\"<b&"), "a\"<b&"),
+            "<a href=\"https://www.codewars.com/users/a%22%3Cb%26\">a\"&lt;b&amp;</a>"
+        );
+    }
+}
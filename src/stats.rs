@@ -1,11 +1,15 @@
-use crate::codewars_requests::{get_completed, get_honor};
-use crate::db::{ChatMessage, CodeUser, UserId};
+use crate::codewars_requests::{get_completed, get_honor, get_user, CodewarsClient};
+use crate::db::{ChatMessage, CodeUser, HonorSnapshot, TrackedLanguages, UserId};
 use crate::error::MainError;
+use crate::kata_match::KataMatcher;
+use crate::message_parse::normalize_kata_name;
+use crate::storage::Storage;
+use crate::utils::DateRange;
 use futures::future::join_all;
-use plotlib::style::BoxStyle;
+use plotlib::style::{BoxStyle, LineStyle};
 use plotlib::{page, repr, view};
 use resvg::usvg;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::once;
 use std::path::PathBuf;
 use svg;
@@ -13,12 +17,18 @@ use uuid;
 
 const SIZE_MULT: u32 = 2;
 const SPACE_LEN: u32 = 40;
+const USER_COLORS: [&str; 8] = [
+    "blue", "orange", "green", "red", "purple", "brown", "magenta", "teal",
+];
 
-pub async fn compute_honor(users: HashMap<UserId, CodeUser>) -> Result<PathBuf, MainError> {
+pub async fn compute_honor(
+    client: &CodewarsClient,
+    users: HashMap<UserId, CodeUser>,
+) -> Result<PathBuf, MainError> {
     let honors = join_all(users.values().cloned().map(|u: CodeUser| async {
         let u = u;
         Result::<_, MainError>::Ok((
-            get_honor(u.codewars_name.as_str()).await?,
+            get_honor(client, u.codewars_name.as_str()).await?,
             u.firstname.to_owned(),
         ))
     }))
@@ -62,24 +72,29 @@ pub async fn compute_honor(users: HashMap<UserId, CodeUser>) -> Result<PathBuf,
 }
 
 pub async fn compute_stats(
+    client: &CodewarsClient,
     users: HashMap<UserId, CodeUser>,
     messages: Vec<ChatMessage>,
+    languages: &TrackedLanguages,
+    range: &DateRange,
 ) -> Result<PathBuf, MainError> {
     let mut user_stats = Vec::new();
     let mut maxy = 5;
     for user in users.values() {
-        let solved_in_scala: Vec<_> = get_completed(user.codewars_name.as_str())
+        let solved: Vec<_> = get_completed(client, user.codewars_name.as_str())
             .await?
             .into_iter()
-            .filter(|k| k.completed_languages.contains(&"scala".to_owned()))
+            .filter(|k| languages.tracks_any_of(&k.completed_languages))
+            .filter(|k| range.contains(k.completed_timestamp()))
             .collect();
         let sent_to_chat = messages
             .iter()
             .filter(|msg| msg.from == user.telegram_id)
+            .filter(|msg| range.contains(msg.date))
             .count();
-        user_stats.push((user.clone(), solved_in_scala.len(), sent_to_chat));
+        user_stats.push((user.clone(), solved.len(), sent_to_chat));
 
-        maxy = maxy.max(solved_in_scala.len().max(sent_to_chat));
+        maxy = maxy.max(solved.len().max(sent_to_chat));
     }
 
     let bars: Vec<repr::BarChart> = user_stats
@@ -124,6 +139,215 @@ pub async fn compute_stats(
     ))
 }
 
+/// Tells how many `messages` are left out of `range` for having no date,
+/// solutions stored before dates were kept are dated only from imported history
+pub fn undated_note(messages: &[ChatMessage], range: &DateRange) -> Option<String> {
+    let undated = messages.iter().filter(|msg| msg.date.is_none()).count();
+    if range.is_all_time() || undated == 0 {
+        None
+    } else {
+        Some(format!(
+            "{} solutions have no date and are only counted for all time",
+            undated
+        ))
+    }
+}
+
+pub async fn compute_ranks(
+    client: &CodewarsClient,
+    users: HashMap<UserId, CodeUser>,
+) -> Result<PathBuf, MainError> {
+    let profiles = join_all(users.values().cloned().map(|u: CodeUser| async {
+        let u = u;
+        Result::<_, MainError>::Ok((
+            get_user(client, u.codewars_name.as_str()).await?,
+            u.firstname.to_owned(),
+        ))
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let languages: BTreeSet<_> = profiles
+        .iter()
+        .flat_map(|(p, _)| p.ranks.languages.keys())
+        .collect();
+    let maxy = profiles
+        .iter()
+        .flat_map(|(p, _)| p.ranks.languages.values().map(|r| r.score))
+        .max()
+        .unwrap_or(50);
+    // bars of the same language stay together, each user keeps one colour
+    let bars: Vec<repr::BarChart> = languages
+        .into_iter()
+        .flat_map(|lang| {
+            profiles
+                .iter()
+                .enumerate()
+                .filter_map(move |(i, (p, name))| {
+                    p.ranks.languages.get(lang).map(|r| {
+                        repr::BarChart::new(r.score as f64)
+                            .label(format!("{} {} ({})", lang, name, r.name))
+                            .style(&BoxStyle::new().fill(USER_COLORS[i % USER_COLORS.len()]))
+                    })
+                })
+        })
+        .collect();
+
+    let mut view = view::CategoricalView::new()
+        .y_range(0., maxy as f64)
+        .x_label("languages")
+        .y_label("rank score");
+
+    let width = bars
+        .iter()
+        .map(|bar| (bar.get_label().chars().count() as u32 + SPACE_LEN) * SIZE_MULT)
+        .sum();
+
+    for bar in bars {
+        view = view.add(bar)
+    }
+
+    Ok(to_image(
+        page::Page::single(&view).dimensions(600.max(width), 600),
+    ))
+}
+
+/// Stores the current honor of every registered user, returns how many were stored
+pub async fn take_honor_snapshots(
+    client: &CodewarsClient,
+    db: &dyn Storage,
+) -> Result<usize, MainError> {
+    let mut taken = 0;
+    for user in db.get_all_users()? {
+        match get_user(client, user.codewars_name.as_str()).await {
+            Ok(profile) => {
+                db.add_honor_snapshot(
+                    user.codewars_name.as_str(),
+                    HonorSnapshot {
+                        taken_at: chrono::Utc::now().timestamp(),
+                        honor: profile.honor,
+                        completed: profile.code_challenges.total_completed,
+                    },
+                )?;
+                taken += 1;
+            }
+            Err(e) => log::warn!("Couldn't snapshot honor of {}: {}", user.codewars_name, e),
+        }
+    }
+    Ok(taken)
+}
+
+pub fn compute_honor_history(
+    db: &dyn Storage,
+    users: HashMap<UserId, CodeUser>,
+    days: i64,
+) -> Result<PathBuf, MainError> {
+    let now = chrono::Utc::now().timestamp();
+    let since = now - days * 24 * 60 * 60;
+
+    let mut view = view::ContinuousView::new()
+        .x_range(-days as f64, 0.)
+        .x_label("days ago")
+        .y_label("honor");
+    let (mut miny, mut maxy) = (i64::max_value(), 0);
+    for (i, user) in users.values().enumerate() {
+        let history = db.get_honor_history(user.codewars_name.as_str(), since)?;
+        if history.is_empty() {
+            continue;
+        }
+        miny = history.iter().map(|s| s.honor).fold(miny, i64::min);
+        maxy = history.iter().map(|s| s.honor).fold(maxy, i64::max);
+        let points = history
+            .iter()
+            .map(|s| {
+                (
+                    (s.taken_at - now) as f64 / (24. * 60. * 60.),
+                    s.honor as f64,
+                )
+            })
+            .collect();
+        view = view.add(
+            repr::Plot::new(points)
+                .line_style(LineStyle::new().colour(USER_COLORS[i % USER_COLORS.len()]))
+                .legend(user.firstname.clone()),
+        );
+    }
+    if miny > maxy {
+        miny = 0;
+        maxy = 50;
+    }
+
+    Ok(to_image(
+        page::Page::single(&view.y_range(miny as f64, maxy.max(miny + 1) as f64))
+            .dimensions(800, 600),
+    ))
+}
+
+/// Discrepancies between a user's Codewars history and their posts in the chat.
+#[derive(Debug)]
+pub struct CheaterReport {
+    pub user: CodeUser,
+    /// Katas completed on Codewars in a tracked language but never posted to the chat
+    pub not_posted: Vec<String>,
+    /// Katas posted to the chat that aren't completed on Codewars in any language
+    pub not_completed: Vec<String>,
+}
+
+impl CheaterReport {
+    pub fn is_clean(&self) -> bool {
+        self.not_posted.is_empty() && self.not_completed.is_empty()
+    }
+}
+
+/// Posted katas are resolved to Codewars ones by `matcher`, see `KataMatcher::resolve`
+pub async fn compute_cheaters(
+    client: &CodewarsClient,
+    users: HashMap<UserId, CodeUser>,
+    messages: Vec<ChatMessage>,
+    languages: &TrackedLanguages,
+    matcher: &mut KataMatcher,
+) -> Result<Vec<CheaterReport>, MainError> {
+    let mut reports = Vec::new();
+    for user in users.values() {
+        let completed = get_completed(client, user.codewars_name.as_str()).await?;
+        let posted: BTreeMap<_, _> = messages
+            .iter()
+            .filter(|msg| msg.from == user.telegram_id)
+            .map(|msg| {
+                (
+                    normalize_kata_name(&msg.submission.kata_name),
+                    &msg.submission,
+                )
+            })
+            .collect();
+        let mut posted_ids = HashSet::new();
+        let mut not_completed = Vec::new();
+        for submission in posted.values() {
+            match matcher.resolve(submission, &completed) {
+                Some((kata, _)) => {
+                    posted_ids.insert(kata.id.as_str());
+                }
+                None => not_completed.push(submission.kata_name.clone()),
+            }
+        }
+        let mut not_posted: Vec<_> = completed
+            .iter()
+            .filter(|k| languages.tracks_any_of(&k.completed_languages))
+            .filter(|k| !posted_ids.contains(k.id.as_str()))
+            .map(|k| k.name.clone())
+            .collect();
+        not_posted.sort_by_key(|name| normalize_kata_name(name));
+
+        reports.push(CheaterReport {
+            user: user.clone(),
+            not_posted,
+            not_completed,
+        });
+    }
+    Ok(reports)
+}
+
 fn to_image(page: page::Page) -> PathBuf {
     let mut bytes = Vec::new();
     svg::write(&mut bytes, &page.to_svg().unwrap()).unwrap();
@@ -150,3 +374,84 @@ fn to_image(page: page::Page) -> PathBuf {
     img.save_png(path.as_path());
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codewars_requests::tests::{client, mock_completed};
+    use crate::message_parse::parse_submission;
+
+    #[test]
+    fn undated_note_test() {
+        let message = |date| ChatMessage {
+            id: 1,
+            from: UserId(1),
+            submission: parse_submission("7\nRobinson Crusoe\nhttps://pastebin.com/fZHdUbhT")
+                .unwrap(),
+            date,
+        };
+        let messages = vec![message(Some(100)), message(None)];
+        let week = DateRange {
+            from: Some(0),
+            to: None,
+        };
+        assert_eq!(
+            undated_note(&messages, &week),
+            Some("1 solutions have no date and are only counted for all time".to_owned())
+        );
+        assert_eq!(undated_note(&messages, &DateRange::default()), None);
+        assert_eq!(undated_note(&messages[..1], &week), None);
+    }
+
+    #[tokio::test]
+    async fn compute_cheaters_test() {
+        let _m = mock_completed("cheating_user");
+        let user = CodeUser {
+            username: None,
+            firstname: "Cheater".to_owned(),
+            telegram_id: UserId(1),
+            codewars_name: "cheating_user".to_owned(),
+        };
+        let messages = vec![
+            ChatMessage {
+                id: 1,
+                from: UserId(1),
+                submission: parse_submission("7\nRobinson Crusoe\nhttps://pastebin.com/fZHdUbhT")
+                    .unwrap(),
+                date: None,
+            },
+            ChatMessage {
+                id: 2,
+                from: UserId(1),
+                submission: parse_submission(
+                    "6\nCreate Phone Number\nhttps://pastebin.com/grekUgAs",
+                )
+                .unwrap(),
+                date: None,
+            },
+        ];
+        let users = once((user.telegram_id, user)).collect();
+
+        let mut matcher = KataMatcher::new(BTreeMap::new());
+        let reports = compute_cheaters(
+            &client(),
+            users,
+            messages,
+            &TrackedLanguages::default(),
+            &mut matcher,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(
+            reports[0].not_posted,
+            vec!["Replace With Alphabet Position"]
+        );
+        assert_eq!(reports[0].not_completed, vec!["Create Phone Number"]);
+        assert_eq!(
+            matcher.resolved()["robinson crusoe"].kata_id,
+            "5899dc03bc95b1bf1b0000ad"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::db::{
+    ChatId, ChatMessage, ChatName, ChatSettings, CodeUser, HonorSnapshot, KataMapping,
+    TrackedLanguages, UserId,
+};
+use crate::error::MainError;
+use smart_default::SmartDefault;
+use std::collections::{BTreeMap, HashMap};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+/// Everything the bot persists: registered users, solution messages,
+/// import state, chat settings and honor history.
+/// Implemented by `Persist` over sled, `SqliteStorage` and `MemoryStorage`.
+pub trait Storage: Send + Sync {
+    fn add_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError>;
+
+    fn add_imported_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError>;
+
+    fn clear_messages(&self, chat_id: ChatId) -> Result<(), MainError>;
+
+    fn clear_imported_messages(&self, chat_id: ChatId) -> Result<(), MainError>;
+
+    /// Messages of a chat ordered by id
+    fn get_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError>;
+
+    /// Imported messages of a chat ordered by id
+    fn get_imported_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError>;
+
+    /// Adds messages imported for `chat_id` to its messages unless that was
+    /// already done, messages with ids the chat already has are kept.
+    /// Returns whether the chat was converted.
+    fn messages_imported_to_regular(&self, chat_id: ChatId) -> Result<bool, MainError>;
+
+    fn is_chat_imported(&self, chat_id: ChatId) -> Result<bool, MainError>;
+
+    fn set_imported(&self, chat_id: ChatId, imported: bool) -> Result<(), MainError>;
+
+    fn reset_imported(&self, chat_id: ChatId) -> Result<(), MainError> {
+        log::info!("reset is_imported for chat {:?}", chat_id);
+        self.set_imported(chat_id, false)
+    }
+
+    /// Remembers the current title of a chat (first name for private chats)
+    fn set_chat_title(&self, chat_id: ChatId, title: ChatName) -> Result<(), MainError>;
+
+    fn chat_title(&self, chat_id: ChatId) -> Result<Option<ChatName>, MainError>;
+
+    /// Chats last seen with `title`, ordered by id
+    fn chats_titled(&self, title: &ChatName) -> Result<Vec<ChatId>, MainError>;
+
+    /// Katas posted names of a chat stand for, keyed by normalized name
+    fn kata_mappings(&self, chat_id: ChatId) -> Result<BTreeMap<String, KataMapping>, MainError>;
+
+    fn set_kata_mapping(
+        &self,
+        chat_id: ChatId,
+        name: String,
+        mapping: KataMapping,
+    ) -> Result<(), MainError>;
+
+    fn remove_kata_mapping(&self, chat_id: ChatId, name: &str) -> Result<(), MainError>;
+
+    /// Chats with registered users, messages, settings, a title or kata mappings, ordered by id
+    fn chats(&self) -> Result<Vec<ChatId>, MainError>;
+
+    /// Chats with imported messages or an import flag, ordered by id
+    fn imported_chats(&self) -> Result<Vec<ChatId>, MainError>;
+
+    fn add_user(&self, chat_id: ChatId, user: CodeUser) -> Result<(), MainError>;
+
+    fn remove_user(&self, chat_id: ChatId, user_to_remove: UserId) -> Result<(), MainError>;
+
+    fn clear_users(&self, chat_id: ChatId) -> Result<(), MainError>;
+
+    fn get_users(&self, chat_id: ChatId) -> Result<HashMap<UserId, CodeUser>, MainError>;
+
+    fn get_settings(&self, chat_id: ChatId) -> Result<ChatSettings, MainError>;
+
+    fn set_settings(&self, chat_id: ChatId, settings: ChatSettings) -> Result<(), MainError>;
+
+    fn set_tracked_languages(
+        &self,
+        chat_id: ChatId,
+        languages: TrackedLanguages,
+    ) -> Result<(), MainError>;
+
+    /// Users registered in any chat, one per Codewars name
+    fn get_all_users(&self) -> Result<Vec<CodeUser>, MainError>;
+
+    fn add_honor_snapshot(
+        &self,
+        codewars_name: &str,
+        snapshot: HonorSnapshot,
+    ) -> Result<(), MainError>;
+
+    /// Snapshots of a user taken at or after `since`, oldest first
+    fn get_honor_history(
+        &self,
+        codewars_name: &str,
+        since: i64,
+    ) -> Result<Vec<HonorSnapshot>, MainError>;
+
+    /// Codewars names with at least one honor snapshot, sorted
+    fn honor_history_names(&self) -> Result<Vec<String>, MainError>;
+
+    /// Makes sure everything written so far survives a restart
+    fn flush(&self) -> Result<(), MainError> {
+        Ok(())
+    }
+}
+
+/// Storage implementation used by the bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SmartDefault)]
+pub enum Backend {
+    #[default]
+    Sled,
+    Sqlite,
+}
+
+impl Backend {
+    /// `STORAGE_BACKEND` set to `sled` (the default) or `sqlite`, data is not
+    /// moved when it changes, `backup::export` and `backup::restore` do that
+    pub fn from_env() -> Self {
+        match std::env::var("STORAGE_BACKEND") {
+            Ok(name) if name.eq_ignore_ascii_case("sqlite") => Backend::Sqlite,
+            Ok(name) if !name.eq_ignore_ascii_case("sled") => {
+                log::warn!("unknown STORAGE_BACKEND {}, using sled", name);
+                Backend::Sled
+            }
+            _ => Backend::Sled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
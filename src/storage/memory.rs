@@ -0,0 +1,283 @@
+use super::Storage;
+use crate::db::{
+    ChatId, ChatMessage, ChatName, ChatSettings, CodeUser, HonorSnapshot, KataMapping,
+    TrackedLanguages, UserId,
+};
+use crate::error::MainError;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct State {
+    users: HashMap<ChatId, HashMap<UserId, CodeUser>>,
+    messages: HashMap<ChatId, BTreeMap<i32, ChatMessage>>,
+    imported_messages: HashMap<ChatId, BTreeMap<i32, ChatMessage>>,
+    was_chat_imported: HashMap<ChatId, bool>,
+    chat_titles: HashMap<ChatId, ChatName>,
+    kata_mappings: HashMap<ChatId, BTreeMap<String, KataMapping>>,
+    settings: HashMap<ChatId, ChatSettings>,
+    honor_history: HashMap<String, BTreeMap<i64, HonorSnapshot>>,
+}
+
+/// Storage kept in process memory and lost on exit, for tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut State) -> T) -> Result<T, MainError> {
+        Ok(f(&mut self.state.lock().unwrap()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn add_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
+        self.with(|s| {
+            s.messages.entry(chat_id).or_default().insert(msg.id, msg);
+        })
+    }
+
+    fn add_imported_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
+        self.with(|s| {
+            s.imported_messages
+                .entry(chat_id)
+                .or_default()
+                .insert(msg.id, msg);
+        })
+    }
+
+    fn clear_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.with(|s| {
+            s.messages.remove(&chat_id);
+        })
+    }
+
+    fn clear_imported_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.with(|s| {
+            s.imported_messages.remove(&chat_id);
+        })
+    }
+
+    fn get_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
+        self.with(|s| {
+            s.messages
+                .get(&chat_id)
+                .map_or(Vec::new(), |m| m.values().cloned().collect())
+        })
+    }
+
+    fn get_imported_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
+        self.with(|s| {
+            s.imported_messages
+                .get(&chat_id)
+                .map_or(Vec::new(), |m| m.values().cloned().collect())
+        })
+    }
+
+    fn messages_imported_to_regular(&self, chat_id: ChatId) -> Result<bool, MainError> {
+        self.with(|s| {
+            if s.was_chat_imported.get(&chat_id) == Some(&true) {
+                return false;
+            }
+            if let Some(imported) = s.imported_messages.get(&chat_id) {
+                let messages = s.messages.entry(chat_id).or_default();
+                for (id, msg) in imported {
+                    messages.entry(*id).or_insert_with(|| msg.clone());
+                }
+            }
+            s.was_chat_imported.insert(chat_id, true);
+            true
+        })
+    }
+
+    fn is_chat_imported(&self, chat_id: ChatId) -> Result<bool, MainError> {
+        self.with(|s| s.was_chat_imported.get(&chat_id) == Some(&true))
+    }
+
+    fn set_imported(&self, chat_id: ChatId, imported: bool) -> Result<(), MainError> {
+        self.with(|s| {
+            s.was_chat_imported.insert(chat_id, imported);
+        })
+    }
+
+    fn set_chat_title(&self, chat_id: ChatId, title: ChatName) -> Result<(), MainError> {
+        self.with(|s| {
+            s.chat_titles.insert(chat_id, title);
+        })
+    }
+
+    fn chat_title(&self, chat_id: ChatId) -> Result<Option<ChatName>, MainError> {
+        self.with(|s| s.chat_titles.get(&chat_id).cloned())
+    }
+
+    fn chats_titled(&self, title: &ChatName) -> Result<Vec<ChatId>, MainError> {
+        self.with(|s| {
+            let mut chats: Vec<_> = s
+                .chat_titles
+                .iter()
+                .filter(|(_, t)| *t == title)
+                .map(|(id, _)| *id)
+                .collect();
+            chats.sort_by_key(|c| c.0);
+            chats
+        })
+    }
+
+    fn kata_mappings(&self, chat_id: ChatId) -> Result<BTreeMap<String, KataMapping>, MainError> {
+        self.with(|s| s.kata_mappings.get(&chat_id).cloned().unwrap_or_default())
+    }
+
+    fn set_kata_mapping(
+        &self,
+        chat_id: ChatId,
+        name: String,
+        mapping: KataMapping,
+    ) -> Result<(), MainError> {
+        self.with(|s| {
+            s.kata_mappings
+                .entry(chat_id)
+                .or_default()
+                .insert(name, mapping);
+        })
+    }
+
+    fn remove_kata_mapping(&self, chat_id: ChatId, name: &str) -> Result<(), MainError> {
+        self.with(|s| {
+            if let Some(mappings) = s.kata_mappings.get_mut(&chat_id) {
+                mappings.remove(name);
+            }
+        })
+    }
+
+    fn chats(&self) -> Result<Vec<ChatId>, MainError> {
+        self.with(|s| {
+            let mut chats: Vec<_> = s
+                .users
+                .keys()
+                .chain(s.messages.keys())
+                .chain(s.settings.keys())
+                .chain(s.chat_titles.keys())
+                .chain(s.kata_mappings.keys())
+                .copied()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            chats.sort_by_key(|c| c.0);
+            chats
+        })
+    }
+
+    fn imported_chats(&self) -> Result<Vec<ChatId>, MainError> {
+        self.with(|s| {
+            let mut chats: Vec<_> = s
+                .imported_messages
+                .keys()
+                .chain(s.was_chat_imported.keys())
+                .copied()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            chats.sort_by_key(|c| c.0);
+            chats
+        })
+    }
+
+    fn add_user(&self, chat_id: ChatId, user: CodeUser) -> Result<(), MainError> {
+        self.with(|s| {
+            s.users
+                .entry(chat_id)
+                .or_default()
+                .insert(user.telegram_id, user);
+        })
+    }
+
+    fn remove_user(&self, chat_id: ChatId, user_to_remove: UserId) -> Result<(), MainError> {
+        self.with(|s| {
+            if let Some(users) = s.users.get_mut(&chat_id) {
+                users.remove(&user_to_remove);
+            }
+        })
+    }
+
+    fn clear_users(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.with(|s| {
+            s.users.remove(&chat_id);
+        })
+    }
+
+    fn get_users(&self, chat_id: ChatId) -> Result<HashMap<UserId, CodeUser>, MainError> {
+        self.with(|s| s.users.get(&chat_id).cloned().unwrap_or_default())
+    }
+
+    fn get_settings(&self, chat_id: ChatId) -> Result<ChatSettings, MainError> {
+        self.with(|s| s.settings.get(&chat_id).cloned().unwrap_or_default())
+    }
+
+    fn set_settings(&self, chat_id: ChatId, settings: ChatSettings) -> Result<(), MainError> {
+        self.with(|s| {
+            s.settings.insert(chat_id, settings);
+        })
+    }
+
+    fn set_tracked_languages(
+        &self,
+        chat_id: ChatId,
+        languages: TrackedLanguages,
+    ) -> Result<(), MainError> {
+        self.with(|s| {
+            s.settings.entry(chat_id).or_default().tracked_languages = languages;
+        })
+    }
+
+    fn get_all_users(&self) -> Result<Vec<CodeUser>, MainError> {
+        self.with(|s| {
+            let users: HashMap<_, _> = s
+                .users
+                .values()
+                .flat_map(|chat| chat.values())
+                .map(|u| (u.codewars_name.clone(), u.clone()))
+                .collect();
+            users.into_iter().map(|(_, u)| u).collect()
+        })
+    }
+
+    fn add_honor_snapshot(
+        &self,
+        codewars_name: &str,
+        snapshot: HonorSnapshot,
+    ) -> Result<(), MainError> {
+        self.with(|s| {
+            s.honor_history
+                .entry(codewars_name.to_owned())
+                .or_default()
+                .insert(snapshot.taken_at, snapshot);
+        })
+    }
+
+    fn get_honor_history(
+        &self,
+        codewars_name: &str,
+        since: i64,
+    ) -> Result<Vec<HonorSnapshot>, MainError> {
+        self.with(|s| {
+            s.honor_history.get(codewars_name).map_or(Vec::new(), |h| {
+                h.range(since..)
+                    .map(|(_, snapshot)| snapshot.clone())
+                    .collect()
+            })
+        })
+    }
+
+    fn honor_history_names(&self) -> Result<Vec<String>, MainError> {
+        self.with(|s| {
+            let mut names: Vec<_> = s.honor_history.keys().cloned().collect();
+            names.sort();
+            names
+        })
+    }
+}
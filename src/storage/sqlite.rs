@@ -0,0 +1,526 @@
+use super::Storage;
+use crate::db::{
+    ChatId, ChatMessage, ChatName, ChatSettings, CodeUser, HonorSnapshot, KataMapping,
+    RawChatMessage, TrackedLanguages, UserId,
+};
+use crate::error::MainError;
+use rusqlite::types::FromSql;
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    chat_id INTEGER NOT NULL,
+    telegram_id INTEGER NOT NULL,
+    user TEXT NOT NULL,
+    PRIMARY KEY (chat_id, telegram_id)
+);
+CREATE TABLE IF NOT EXISTS messages (
+    chat_id INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (chat_id, id)
+);
+CREATE TABLE IF NOT EXISTS imported_messages (
+    chat_id INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (chat_id, id)
+);
+CREATE TABLE IF NOT EXISTS imported_chats (
+    chat_id INTEGER PRIMARY KEY,
+    imported INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS chat_titles (
+    chat_id INTEGER PRIMARY KEY,
+    title TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS kata_mappings (
+    chat_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    mapping TEXT NOT NULL,
+    PRIMARY KEY (chat_id, name)
+);
+CREATE TABLE IF NOT EXISTS settings (
+    chat_id INTEGER PRIMARY KEY,
+    settings TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS honor_history (
+    codewars_name TEXT NOT NULL,
+    taken_at INTEGER NOT NULL,
+    snapshot TEXT NOT NULL,
+    PRIMARY KEY (codewars_name, taken_at)
+);
+";
+
+/// `user_version` of databases created with `SCHEMA`
+const SCHEMA_VERSION: i64 = 3;
+
+/// Storage in a SQLite database, rows hold JSON encoded values
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, MainError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    pub fn new(mut conn: Connection) -> Result<Self, MainError> {
+        let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        if version < 1 {
+            // imports used to be keyed by chat title, pending ones have to be imported again
+            conn.execute_batch(
+                "DROP TABLE IF EXISTS imported_messages; DROP TABLE IF EXISTS imported_chats;",
+            )?;
+        }
+        conn.execute_batch(SCHEMA)?;
+        if version < 2 {
+            parse_submissions(&mut conn)?;
+        }
+        if version < 3 {
+            date_from_imports(&mut conn)?;
+        }
+        conn.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> Result<(), MainError> {
+        self.conn.lock().unwrap().execute(sql, params)?;
+        Ok(())
+    }
+
+    /// First column of every row
+    fn query_column<T: FromSql>(
+        &self,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<T>, MainError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement
+            .query_map(params, |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Decodes the JSON in the first column of every row
+    fn query<T: DeserializeOwned>(
+        &self,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<T>, MainError> {
+        self.query_column::<String>(sql, params)?
+            .iter()
+            .map(|json| Ok(serde_json::from_str(json)?))
+            .collect()
+    }
+}
+
+/// Replaces the text of stored solutions with their parsed form, solutions
+/// that don't parse are moved as they are to `unparsed_<table>`
+fn parse_submissions(conn: &mut Connection) -> Result<(), MainError> {
+    let tx = conn.transaction()?;
+    for table in &["messages", "imported_messages"] {
+        tx.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS unparsed_{} (
+                chat_id INTEGER NOT NULL,
+                id INTEGER NOT NULL,
+                message TEXT NOT NULL,
+                PRIMARY KEY (chat_id, id)
+            );",
+            table
+        ))?;
+        let rows = tx
+            .prepare(&format!("SELECT chat_id, id, message FROM {}", table))?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<(i64, i32, String)>, _>>()?;
+        for (chat_id, id, json) in rows {
+            match serde_json::from_str::<RawChatMessage>(&json)?.parse() {
+                Ok(msg) => tx.execute(
+                    &format!(
+                        "UPDATE {} SET message = ?3 WHERE chat_id = ?1 AND id = ?2",
+                        table
+                    ),
+                    params![chat_id, id, serde_json::to_string(&msg)?],
+                )?,
+                Err(e) => {
+                    log::warn!(
+                        "moved stored message {} in chat {} to unparsed_{}: {}",
+                        id,
+                        chat_id,
+                        table,
+                        e
+                    );
+                    tx.execute(
+                        &format!(
+                            "INSERT OR REPLACE INTO unparsed_{} (chat_id, id, message)
+                             VALUES (?1, ?2, ?3)",
+                            table
+                        ),
+                        params![chat_id, id, json],
+                    )?;
+                    tx.execute(
+                        &format!("DELETE FROM {} WHERE chat_id = ?1 AND id = ?2", table),
+                        params![chat_id, id],
+                    )?
+                }
+            };
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Gives solutions stored before dates were kept the date of the same message
+/// in the imported history, the rest stay undated
+fn date_from_imports(conn: &mut Connection) -> Result<(), MainError> {
+    let tx = conn.transaction()?;
+    let rows = tx
+        .prepare(
+            "SELECT m.chat_id, m.id, m.message, i.message FROM messages m
+             JOIN imported_messages i ON i.chat_id = m.chat_id AND i.id = m.id",
+        )?
+        .query_map(params![], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<Vec<(i64, i32, String, String)>, _>>()?;
+    let mut dated = 0;
+    for (chat_id, id, msg, imported) in rows {
+        let msg: ChatMessage = serde_json::from_str(&msg)?;
+        let imported: ChatMessage = serde_json::from_str(&imported)?;
+        if let (None, Some(date)) = (msg.date, imported.date) {
+            let msg = ChatMessage {
+                date: Some(date),
+                ..msg
+            };
+            tx.execute(
+                "UPDATE messages SET message = ?3 WHERE chat_id = ?1 AND id = ?2",
+                params![chat_id, id, serde_json::to_string(&msg)?],
+            )?;
+            dated += 1;
+        }
+    }
+    tx.commit()?;
+    log::info!("dated {} stored solutions from imported history", dated);
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn add_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO messages (chat_id, id, message) VALUES (?1, ?2, ?3)",
+            params![chat_id.0, msg.id, serde_json::to_string(&msg)?],
+        )?;
+        log::info!("message {:?} added to chat {:?}", &msg, &chat_id);
+        Ok(())
+    }
+
+    fn add_imported_message(&self, chat_id: ChatId, msg: ChatMessage) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO imported_messages (chat_id, id, message) VALUES (?1, ?2, ?3)",
+            params![chat_id.0, msg.id, serde_json::to_string(&msg)?],
+        )?;
+        log::info!("imported message {:?} added to chat {:?}", &msg, &chat_id);
+        Ok(())
+    }
+
+    fn clear_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.execute(
+            "DELETE FROM messages WHERE chat_id = ?1",
+            params![chat_id.0],
+        )?;
+        log::info!("messages cleared in chat {:?}", &chat_id);
+        Ok(())
+    }
+
+    fn clear_imported_messages(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.execute(
+            "DELETE FROM imported_messages WHERE chat_id = ?1",
+            params![chat_id.0],
+        )?;
+        log::info!("imported messages cleared in chat {:?}", &chat_id);
+        Ok(())
+    }
+
+    fn get_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
+        self.query(
+            "SELECT message FROM messages WHERE chat_id = ?1 ORDER BY id",
+            params![chat_id.0],
+        )
+    }
+
+    fn get_imported_messages(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, MainError> {
+        self.query(
+            "SELECT message FROM imported_messages WHERE chat_id = ?1 ORDER BY id",
+            params![chat_id.0],
+        )
+    }
+
+    fn messages_imported_to_regular(&self, chat_id: ChatId) -> Result<bool, MainError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let imported: Option<bool> = tx
+            .query_row(
+                "SELECT imported FROM imported_chats WHERE chat_id = ?1",
+                params![chat_id.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if imported == Some(true) {
+            return Ok(false);
+        }
+        let merged = tx.execute(
+            "INSERT OR IGNORE INTO messages (chat_id, id, message)
+             SELECT chat_id, id, message FROM imported_messages WHERE chat_id = ?1",
+            params![chat_id.0],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO imported_chats (chat_id, imported) VALUES (?1, 1)",
+            params![chat_id.0],
+        )?;
+        tx.commit()?;
+        log::info!(
+            "merged {} imported messages into chat {:?}",
+            merged,
+            &chat_id
+        );
+        Ok(true)
+    }
+
+    fn is_chat_imported(&self, chat_id: ChatId) -> Result<bool, MainError> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT imported FROM imported_chats WHERE chat_id = ?1",
+                params![chat_id.0],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false))
+    }
+
+    fn set_imported(&self, chat_id: ChatId, imported: bool) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO imported_chats (chat_id, imported) VALUES (?1, ?2)",
+            params![chat_id.0, imported],
+        )
+    }
+
+    fn set_chat_title(&self, chat_id: ChatId, title: ChatName) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO chat_titles (chat_id, title) VALUES (?1, ?2)",
+            params![chat_id.0, title.0],
+        )
+    }
+
+    fn chat_title(&self, chat_id: ChatId) -> Result<Option<ChatName>, MainError> {
+        Ok(self
+            .query_column(
+                "SELECT title FROM chat_titles WHERE chat_id = ?1",
+                params![chat_id.0],
+            )?
+            .pop()
+            .map(ChatName))
+    }
+
+    fn chats_titled(&self, title: &ChatName) -> Result<Vec<ChatId>, MainError> {
+        Ok(self
+            .query_column(
+                "SELECT chat_id FROM chat_titles WHERE title = ?1 ORDER BY chat_id",
+                params![title.0],
+            )?
+            .into_iter()
+            .map(ChatId)
+            .collect())
+    }
+
+    fn kata_mappings(&self, chat_id: ChatId) -> Result<BTreeMap<String, KataMapping>, MainError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT name, mapping FROM kata_mappings WHERE chat_id = ?1")?;
+        let rows = statement
+            .query_map(params![chat_id.0], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        rows.into_iter()
+            .map(|(name, json)| Ok((name, serde_json::from_str(&json)?)))
+            .collect()
+    }
+
+    fn set_kata_mapping(
+        &self,
+        chat_id: ChatId,
+        name: String,
+        mapping: KataMapping,
+    ) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO kata_mappings (chat_id, name, mapping) VALUES (?1, ?2, ?3)",
+            params![chat_id.0, name, serde_json::to_string(&mapping)?],
+        )
+    }
+
+    fn remove_kata_mapping(&self, chat_id: ChatId, name: &str) -> Result<(), MainError> {
+        self.execute(
+            "DELETE FROM kata_mappings WHERE chat_id = ?1 AND name = ?2",
+            params![chat_id.0, name],
+        )
+    }
+
+    fn chats(&self) -> Result<Vec<ChatId>, MainError> {
+        Ok(self
+            .query_column(
+                "SELECT chat_id FROM users UNION SELECT chat_id FROM messages
+                 UNION SELECT chat_id FROM settings UNION SELECT chat_id FROM chat_titles
+                 UNION SELECT chat_id FROM kata_mappings ORDER BY chat_id",
+                params![],
+            )?
+            .into_iter()
+            .map(ChatId)
+            .collect())
+    }
+
+    fn imported_chats(&self) -> Result<Vec<ChatId>, MainError> {
+        Ok(self
+            .query_column(
+                "SELECT chat_id FROM imported_messages
+                 UNION SELECT chat_id FROM imported_chats ORDER BY chat_id",
+                params![],
+            )?
+            .into_iter()
+            .map(ChatId)
+            .collect())
+    }
+
+    fn add_user(&self, chat_id: ChatId, user: CodeUser) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO users (chat_id, telegram_id, user) VALUES (?1, ?2, ?3)",
+            params![chat_id.0, user.telegram_id.0, serde_json::to_string(&user)?],
+        )?;
+        log::info!("user {:?} added in chat {:?}", &user, &chat_id);
+        Ok(())
+    }
+
+    fn remove_user(&self, chat_id: ChatId, user_to_remove: UserId) -> Result<(), MainError> {
+        self.execute(
+            "DELETE FROM users WHERE chat_id = ?1 AND telegram_id = ?2",
+            params![chat_id.0, user_to_remove.0],
+        )?;
+        log::info!("user {:?} removed in chat {:?}", &user_to_remove, &chat_id);
+        Ok(())
+    }
+
+    fn clear_users(&self, chat_id: ChatId) -> Result<(), MainError> {
+        self.execute("DELETE FROM users WHERE chat_id = ?1", params![chat_id.0])?;
+        log::info!("users cleared in chat {:?}", &chat_id);
+        Ok(())
+    }
+
+    fn get_users(&self, chat_id: ChatId) -> Result<HashMap<UserId, CodeUser>, MainError> {
+        Ok(self
+            .query::<CodeUser>(
+                "SELECT user FROM users WHERE chat_id = ?1",
+                params![chat_id.0],
+            )?
+            .into_iter()
+            .map(|u| (u.telegram_id, u))
+            .collect())
+    }
+
+    fn get_settings(&self, chat_id: ChatId) -> Result<ChatSettings, MainError> {
+        Ok(self
+            .query(
+                "SELECT settings FROM settings WHERE chat_id = ?1",
+                params![chat_id.0],
+            )?
+            .pop()
+            .unwrap_or_default())
+    }
+
+    fn set_settings(&self, chat_id: ChatId, settings: ChatSettings) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO settings (chat_id, settings) VALUES (?1, ?2)",
+            params![chat_id.0, serde_json::to_string(&settings)?],
+        )
+    }
+
+    fn set_tracked_languages(
+        &self,
+        chat_id: ChatId,
+        languages: TrackedLanguages,
+    ) -> Result<(), MainError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let settings: Option<String> = tx
+            .query_row(
+                "SELECT settings FROM settings WHERE chat_id = ?1",
+                params![chat_id.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let settings = ChatSettings {
+            tracked_languages: languages,
+            ..match settings {
+                Some(json) => serde_json::from_str(&json)?,
+                None => ChatSettings::default(),
+            }
+        };
+        tx.execute(
+            "INSERT OR REPLACE INTO settings (chat_id, settings) VALUES (?1, ?2)",
+            params![chat_id.0, serde_json::to_string(&settings)?],
+        )?;
+        tx.commit()?;
+        log::info!("tracked languages changed in chat {:?}", &chat_id);
+        Ok(())
+    }
+
+    fn get_all_users(&self) -> Result<Vec<CodeUser>, MainError> {
+        let users: HashMap<_, _> = self
+            .query::<CodeUser>("SELECT user FROM users", params![])?
+            .into_iter()
+            .map(|u| (u.codewars_name.clone(), u))
+            .collect();
+        Ok(users.into_iter().map(|(_, u)| u).collect())
+    }
+
+    fn add_honor_snapshot(
+        &self,
+        codewars_name: &str,
+        snapshot: HonorSnapshot,
+    ) -> Result<(), MainError> {
+        self.execute(
+            "INSERT OR REPLACE INTO honor_history (codewars_name, taken_at, snapshot)
+             VALUES (?1, ?2, ?3)",
+            params![
+                codewars_name,
+                snapshot.taken_at,
+                serde_json::to_string(&snapshot)?
+            ],
+        )
+    }
+
+    fn get_honor_history(
+        &self,
+        codewars_name: &str,
+        since: i64,
+    ) -> Result<Vec<HonorSnapshot>, MainError> {
+        self.query(
+            "SELECT snapshot FROM honor_history
+             WHERE codewars_name = ?1 AND taken_at >= ?2 ORDER BY taken_at",
+            params![codewars_name, since],
+        )
+    }
+
+    fn honor_history_names(&self) -> Result<Vec<String>, MainError> {
+        self.query_column(
+            "SELECT DISTINCT codewars_name FROM honor_history ORDER BY codewars_name",
+            params![],
+        )
+    }
+}
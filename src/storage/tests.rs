@@ -0,0 +1,287 @@
+//! Conformance suite every `Storage` implementation has to pass
+
+use super::*;
+use crate::db::tests::{message, temporary_persist};
+
+fn user(id: i32, codewars_name: &str) -> CodeUser {
+    CodeUser {
+        username: None,
+        firstname: format!("user{}", id),
+        telegram_id: UserId(id),
+        codewars_name: codewars_name.to_owned(),
+    }
+}
+
+fn ids(messages: Vec<ChatMessage>) -> Vec<i32> {
+    messages.into_iter().map(|m| m.id).collect()
+}
+
+fn users(db: &dyn Storage) {
+    db.add_user(ChatId(-100), user(1, "one")).unwrap();
+    db.add_user(ChatId(-100), user(2, "two")).unwrap();
+    db.add_user(ChatId(-100), user(2, "two_renamed")).unwrap();
+    db.add_user(ChatId(-200), user(3, "one")).unwrap();
+
+    let chat = db.get_users(ChatId(-100)).unwrap();
+    assert_eq!(chat.len(), 2);
+    assert_eq!(chat[&UserId(2)].codewars_name, "two_renamed");
+    assert_eq!(db.get_all_users().unwrap().len(), 2);
+
+    db.remove_user(ChatId(-100), UserId(1)).unwrap();
+    assert_eq!(
+        db.get_users(ChatId(-100))
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>(),
+        vec![&UserId(2)]
+    );
+    db.clear_users(ChatId(-100)).unwrap();
+    assert!(db.get_users(ChatId(-100)).unwrap().is_empty());
+    assert_eq!(db.get_users(ChatId(-200)).unwrap().len(), 1);
+    assert!(db.get_users(ChatId(-300)).unwrap().is_empty());
+}
+
+fn messages(db: &dyn Storage) {
+    db.add_message(ChatId(-100), message(2)).unwrap();
+    db.add_message(ChatId(-100), message(1)).unwrap();
+    db.add_message(ChatId(-200), message(3)).unwrap();
+
+    assert_eq!(ids(db.get_messages(ChatId(-100)).unwrap()), vec![1, 2]);
+    db.clear_messages(ChatId(-100)).unwrap();
+    assert!(db.get_messages(ChatId(-100)).unwrap().is_empty());
+    assert_eq!(ids(db.get_messages(ChatId(-200)).unwrap()), vec![3]);
+}
+
+fn import_state(db: &dyn Storage) {
+    let chat = ChatId(-100);
+    db.add_imported_message(chat, message(2)).unwrap();
+    db.add_imported_message(chat, message(1)).unwrap();
+    db.add_imported_message(ChatId(-200), message(3)).unwrap();
+    db.add_message(chat, message(5)).unwrap();
+    let mut live = message(2);
+    live.submission.kata_name = "live".to_owned();
+    db.add_message(chat, live).unwrap();
+    assert_eq!(ids(db.get_imported_messages(chat).unwrap()), vec![1, 2]);
+    assert!(!db.is_chat_imported(chat).unwrap());
+
+    assert!(db.messages_imported_to_regular(chat).unwrap());
+    db.add_message(chat, message(6)).unwrap();
+    assert!(!db.messages_imported_to_regular(chat).unwrap());
+    assert_eq!(ids(db.get_messages(chat).unwrap()), vec![1, 2, 5, 6]);
+    assert_eq!(
+        db.get_messages(chat).unwrap()[1].submission.kata_name,
+        "live"
+    );
+    assert!(db.is_chat_imported(chat).unwrap());
+
+    db.reset_imported(chat).unwrap();
+    assert!(db.messages_imported_to_regular(chat).unwrap());
+    assert_eq!(ids(db.get_messages(chat).unwrap()), vec![1, 2, 5, 6]);
+    assert!(!db.is_chat_imported(ChatId(-200)).unwrap());
+
+    db.reset_imported(chat).unwrap();
+    db.clear_imported_messages(chat).unwrap();
+    assert!(!db.is_chat_imported(chat).unwrap());
+    assert!(db.get_imported_messages(chat).unwrap().is_empty());
+    assert_eq!(
+        ids(db.get_imported_messages(ChatId(-200)).unwrap()),
+        vec![3]
+    );
+}
+
+fn chat_titles(db: &dyn Storage) {
+    let title = ChatName("chat".to_owned());
+    db.set_chat_title(ChatId(-200), title.clone()).unwrap();
+    db.set_chat_title(ChatId(-100), title.clone()).unwrap();
+    db.set_chat_title(ChatId(-300), ChatName("other".to_owned()))
+        .unwrap();
+    assert_eq!(
+        db.chats_titled(&title).unwrap(),
+        vec![ChatId(-200), ChatId(-100)]
+    );
+
+    db.set_chat_title(ChatId(-100), ChatName("renamed".to_owned()))
+        .unwrap();
+    assert_eq!(db.chats_titled(&title).unwrap(), vec![ChatId(-200)]);
+    assert_eq!(
+        db.chat_title(ChatId(-100)).unwrap(),
+        Some(ChatName("renamed".to_owned()))
+    );
+    assert_eq!(db.chat_title(ChatId(-400)).unwrap(), None);
+}
+
+fn kata_mappings(db: &dyn Storage) {
+    let mapping = |kata_id: &str, manual| KataMapping {
+        kata_id: kata_id.to_owned(),
+        manual,
+    };
+    db.set_kata_mapping(
+        ChatId(-100),
+        "robinson crusoe".to_owned(),
+        mapping("a", false),
+    )
+    .unwrap();
+    db.set_kata_mapping(ChatId(-100), "even or odd".to_owned(), mapping("b", false))
+        .unwrap();
+    db.set_kata_mapping(
+        ChatId(-100),
+        "robinson crusoe".to_owned(),
+        mapping("c", true),
+    )
+    .unwrap();
+    db.set_kata_mapping(ChatId(-200), "even or odd".to_owned(), mapping("d", false))
+        .unwrap();
+
+    let mappings = db.kata_mappings(ChatId(-100)).unwrap();
+    assert_eq!(
+        mappings.keys().collect::<Vec<_>>(),
+        vec!["even or odd", "robinson crusoe"]
+    );
+    assert_eq!(mappings["robinson crusoe"], mapping("c", true));
+
+    db.remove_kata_mapping(ChatId(-100), "even or odd").unwrap();
+    db.remove_kata_mapping(ChatId(-300), "even or odd").unwrap();
+    assert_eq!(db.kata_mappings(ChatId(-100)).unwrap().len(), 1);
+    assert_eq!(
+        db.kata_mappings(ChatId(-200)).unwrap()["even or odd"],
+        mapping("d", false)
+    );
+    assert!(db.kata_mappings(ChatId(-300)).unwrap().is_empty());
+}
+
+fn settings(db: &dyn Storage) {
+    assert_eq!(
+        db.get_settings(ChatId(-100)).unwrap().tracked_languages,
+        TrackedLanguages::default()
+    );
+    db.set_tracked_languages(ChatId(-100), TrackedLanguages::Any)
+        .unwrap();
+    assert_eq!(
+        db.get_settings(ChatId(-100)).unwrap().tracked_languages,
+        TrackedLanguages::Any
+    );
+    assert_eq!(
+        db.get_settings(ChatId(-200)).unwrap().tracked_languages,
+        TrackedLanguages::default()
+    );
+}
+
+fn honor_history(db: &dyn Storage) {
+    for (taken_at, honor) in &[(300, 30), (100, 10), (200, 20)] {
+        let snapshot = HonorSnapshot {
+            taken_at: *taken_at,
+            honor: *honor,
+            completed: 1,
+        };
+        db.add_honor_snapshot("one", snapshot).unwrap();
+    }
+    // shares a prefix with "one", none of its snapshots belong to "one"
+    db.add_honor_snapshot(
+        "ones",
+        HonorSnapshot {
+            taken_at: 250,
+            honor: 1,
+            completed: 1,
+        },
+    )
+    .unwrap();
+
+    let history = db.get_honor_history("one", 200).unwrap();
+    assert_eq!(
+        history.iter().map(|s| s.honor).collect::<Vec<_>>(),
+        vec![20, 30]
+    );
+    assert_eq!(db.honor_history_names().unwrap(), vec!["one", "ones"]);
+}
+
+fn listing(db: &dyn Storage) {
+    db.add_user(ChatId(-300), user(1, "one")).unwrap();
+    db.add_message(ChatId(-100), message(1)).unwrap();
+    db.set_settings(ChatId(-200), ChatSettings::default())
+        .unwrap();
+    db.add_message(ChatId(-100), message(2)).unwrap();
+    db.set_chat_title(ChatId(5), ChatName("private".to_owned()))
+        .unwrap();
+    db.set_kata_mapping(
+        ChatId(-600),
+        "kata".to_owned(),
+        KataMapping {
+            kata_id: "a".to_owned(),
+            manual: true,
+        },
+    )
+    .unwrap();
+    db.add_imported_message(ChatId(-500), message(1)).unwrap();
+    db.set_imported(ChatId(-400), true).unwrap();
+    db.set_imported(ChatId(-500), true).unwrap();
+
+    assert_eq!(
+        db.chats().unwrap(),
+        vec![
+            ChatId(-600),
+            ChatId(-300),
+            ChatId(-200),
+            ChatId(-100),
+            ChatId(5)
+        ]
+    );
+    assert_eq!(
+        db.imported_chats().unwrap(),
+        vec![ChatId(-500), ChatId(-400)]
+    );
+}
+
+macro_rules! conformance {
+    ($backend:ident, $storage:expr) => {
+        mod $backend {
+            use super::*;
+
+            #[test]
+            fn users_test() {
+                users(&$storage)
+            }
+
+            #[test]
+            fn messages_test() {
+                messages(&$storage)
+            }
+
+            #[test]
+            fn import_state_test() {
+                import_state(&$storage)
+            }
+
+            #[test]
+            fn chat_titles_test() {
+                chat_titles(&$storage)
+            }
+
+            #[test]
+            fn kata_mappings_test() {
+                kata_mappings(&$storage)
+            }
+
+            #[test]
+            fn settings_test() {
+                settings(&$storage)
+            }
+
+            #[test]
+            fn listing_test() {
+                listing(&$storage)
+            }
+
+            #[test]
+            fn honor_history_test() {
+                honor_history(&$storage)
+            }
+        }
+    };
+}
+
+conformance!(on_sled, temporary_persist());
+conformance!(on_memory, MemoryStorage::new());
+conformance!(
+    on_sqlite,
+    SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap()
+);
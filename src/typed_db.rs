@@ -1,38 +1,318 @@
 use crate::error::MainError;
 use serde::{de::DeserializeOwned, Serialize};
+use sled::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
+use std::cell::RefCell;
 use std::marker::PhantomData;
 
-pub struct TypedDb<K, V> {
-    inner: sled::Db,
-    kv: PhantomData<(K, V)>,
+pub mod key;
+
+/// How `TypedDb` turns keys and values into bytes
+pub trait Codec {
+    fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>, MainError>;
+    fn decode_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K, MainError>;
+    fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>, MainError>;
+    fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, MainError>;
+    /// Bytes every encoded `(first, _)` key starts with
+    fn tuple_prefix<P: Serialize>(first: &P) -> Result<Vec<u8>, MainError>;
+}
+
+/// JSON keys and values, the layout before schema version 3
+pub struct Json;
+
+impl Codec for Json {
+    fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>, MainError> {
+        Ok(serde_json::to_vec(key)?)
+    }
+
+    fn decode_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K, MainError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>, MainError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, MainError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn tuple_prefix<P: Serialize>(first: &P) -> Result<Vec<u8>, MainError> {
+        // `(first,)` encodes as `[first]`, every `(first, _)` key starts with `[first,`
+        let mut prefix = serde_json::to_vec(&(first,))?;
+        prefix.pop();
+        prefix.push(b',');
+        Ok(prefix)
+    }
 }
 
-impl<K, V> TypedDb<K, V>
+/// Order-preserving binary keys (see `key`) and CBOR values
+pub struct Binary;
+
+impl Codec for Binary {
+    fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>, MainError> {
+        Ok(key::to_vec(key)?)
+    }
+
+    fn decode_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K, MainError> {
+        Ok(key::from_slice(bytes)?)
+    }
+
+    fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>, MainError> {
+        Ok(serde_cbor::to_vec(value)?)
+    }
+
+    fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, MainError> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+
+    fn tuple_prefix<P: Serialize>(first: &P) -> Result<Vec<u8>, MainError> {
+        // tuples encode as their elements one after another
+        Ok(key::to_vec(first)?)
+    }
+}
+
+pub struct TypedDb<K, V, C = Binary> {
+    inner: sled::Tree,
+    kv: PhantomData<(K, V, C)>,
+}
+
+impl<K, V, C> TypedDb<K, V, C>
 where
     K: Serialize + DeserializeOwned,
     V: Serialize + DeserializeOwned,
+    C: Codec,
 {
-    pub fn new(db: sled::Db) -> Self {
+    pub fn new(tree: sled::Tree) -> Self {
         Self {
-            inner: db,
+            inner: tree,
             kv: PhantomData::default(),
         }
     }
 
+    /// View of the same tree with different key and value types
+    pub fn retyped<K2, V2>(&self) -> TypedDb<K2, V2, C>
+    where
+        K2: Serialize + DeserializeOwned,
+        V2: Serialize + DeserializeOwned,
+    {
+        TypedDb::new(self.inner.clone())
+    }
+
+    /// View of the same tree encoded with a different codec
+    pub fn with_codec<C2: Codec>(&self) -> TypedDb<K, V, C2> {
+        TypedDb::new(self.inner.clone())
+    }
+
     pub fn get(&self, key: &K) -> Result<Option<V>, MainError> {
-        self.inner
-            .get(serde_json::to_vec(key)?.as_slice())?
-            .map(|v| Ok(serde_json::from_slice(v.as_ref())?))
-            .map_or(Ok(None), |r| r.map(Some))
+        let value = self.inner.get(C::encode_key(key)?)?;
+        Self::decode(value.as_ref())
     }
 
     pub fn insert(&self, key: &K, value: V) -> Result<(), MainError> {
+        self.inner
+            .insert(C::encode_key(key)?, C::encode_value(&value)?)?;
+        Ok(())
+    }
+
+    /// Atomically replaces the value under `key` with `f(old)`, `None` removes it.
+    /// `f` is called again whenever another writer changed the value in between.
+    /// Returns the new value.
+    pub fn update<F>(&self, key: &K, f: F) -> Result<Option<V>, MainError>
+    where
+        F: FnMut(Option<V>) -> Option<V>,
+    {
+        Ok(self.compare_and_swap_loop(key, f)?.1)
+    }
+
+    /// Same as `update` but returns the value before the update
+    pub fn fetch_and_update<F>(&self, key: &K, f: F) -> Result<Option<V>, MainError>
+    where
+        F: FnMut(Option<V>) -> Option<V>,
+    {
+        Ok(self.compare_and_swap_loop(key, f)?.0)
+    }
+
+    fn compare_and_swap_loop<F>(
+        &self,
+        key: &K,
+        mut f: F,
+    ) -> Result<(Option<V>, Option<V>), MainError>
+    where
+        F: FnMut(Option<V>) -> Option<V>,
+    {
+        let key = C::encode_key(key)?;
+        loop {
+            let old_bytes = self.inner.get(key.as_slice())?;
+            let new = f(Self::decode(old_bytes.as_ref())?);
+            let new_bytes = new.as_ref().map(C::encode_value).transpose()?;
+            if self
+                .inner
+                .compare_and_swap(key.as_slice(), old_bytes.as_ref(), new_bytes)?
+                .is_ok()
+            {
+                return Ok((Self::decode(old_bytes.as_ref())?, new));
+            }
+        }
+    }
+
+    fn decode(value: Option<&sled::IVec>) -> Result<Option<V>, MainError> {
+        value.map(|v| C::decode_value(v.as_ref())).transpose()
+    }
+
+    fn decode_entry((k, v): (sled::IVec, sled::IVec)) -> Result<(K, V), MainError> {
+        Ok((C::decode_key(k.as_ref())?, C::decode_value(v.as_ref())?))
+    }
+
+    pub fn remove(&self, key: &K) -> Result<Option<V>, MainError> {
+        let value = self.inner.remove(C::encode_key(key)?)?;
+        Self::decode(value.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, V), MainError>> {
+        self.inner.iter().map(|kv| Self::decode_entry(kv?))
+    }
+
+    pub fn clear(&self) -> Result<(), MainError> {
+        Ok(self.inner.clear()?)
+    }
+
+    /// Entries that decode as `K` and `V`, the rest of the tree is skipped
+    pub fn iter_decodable(&self) -> impl Iterator<Item = Result<(K, V), MainError>> {
+        self.inner.iter().filter_map(|kv| match kv {
+            Ok(kv) => Self::decode_entry(kv).ok().map(Ok),
+            Err(e) => Some(Err(e.into())),
+        })
+    }
+}
+
+impl<P, S, V, C> TypedDb<(P, S), V, C>
+where
+    P: Serialize + DeserializeOwned,
+    S: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Entries whose key starts with `first`, in key order
+    pub fn scan_first(
+        &self,
+        first: &P,
+    ) -> Result<impl Iterator<Item = Result<((P, S), V), MainError>>, MainError> {
+        Ok(self
+            .inner
+            .scan_prefix(C::tuple_prefix(first)?)
+            .map(|kv| Self::decode_entry(kv?)))
+    }
+
+    /// Entries whose key starts with `first` and is not below `(first, from)`, in key order
+    pub fn scan_first_from(
+        &self,
+        first: &P,
+        from: &S,
+    ) -> Result<impl Iterator<Item = Result<((P, S), V), MainError>>, MainError> {
+        let prefix = C::tuple_prefix(first)?;
         Ok(self
             .inner
-            .insert(
-                serde_json::to_vec(key)?.as_slice(),
-                serde_json::to_vec(&value)?.as_slice(),
-            )
-            .map(|_| ())?)
+            .range(C::encode_key(&(first, from))?..)
+            .take_while(move |kv| kv.as_ref().map_or(true, |(k, _)| k.starts_with(&prefix)))
+            .map(|kv| Self::decode_entry(kv?)))
+    }
+
+    /// Removes every entry whose key starts with `first`, returns how many were removed
+    pub fn remove_first(&self, first: &P) -> Result<usize, MainError> {
+        let keys = self
+            .scan_first(first)?
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in keys.iter() {
+            self.remove(key)?;
+        }
+        Ok(keys.len())
     }
 }
+
+/// Typed view of a tree inside a sled transaction
+pub struct TypedTransactionalTree<'a, K, V, C = Binary> {
+    tree: &'a TransactionalTree,
+    error: &'a RefCell<Option<MainError>>,
+    kv: PhantomData<(K, V, C)>,
+}
+
+impl<'a, K, V, C> TypedTransactionalTree<'a, K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Aborts the transaction, `transaction2` returns `e`
+    fn abort<T>(&self, e: MainError) -> ConflictableTransactionResult<T, ()> {
+        self.error.replace(Some(e));
+        Err(ConflictableTransactionError::Abort(()))
+    }
+
+    pub fn get(&self, key: &K) -> ConflictableTransactionResult<Option<V>, ()> {
+        let key = C::encode_key(key).or_else(|e| self.abort(e))?;
+        match self.tree.get(key)? {
+            Some(v) => C::decode_value(v.as_ref())
+                .map(Some)
+                .or_else(|e| self.abort(e)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> ConflictableTransactionResult<(), ()> {
+        let key = C::encode_key(key).or_else(|e| self.abort(e))?;
+        let value = C::encode_value(value).or_else(|e| self.abort(e))?;
+        self.tree.insert(key, value)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &K) -> ConflictableTransactionResult<(), ()> {
+        let key = C::encode_key(key).or_else(|e| self.abort(e))?;
+        self.tree.remove(key)?;
+        Ok(())
+    }
+}
+
+/// Runs `f` over two trees of the same database atomically, retrying it on conflicts
+pub fn transaction2<K1, V1, C1, K2, V2, C2, A, F>(
+    a: &TypedDb<K1, V1, C1>,
+    b: &TypedDb<K2, V2, C2>,
+    f: F,
+) -> Result<A, MainError>
+where
+    K1: Serialize + DeserializeOwned,
+    V1: Serialize + DeserializeOwned,
+    C1: Codec,
+    K2: Serialize + DeserializeOwned,
+    V2: Serialize + DeserializeOwned,
+    C2: Codec,
+    F: Fn(
+        &TypedTransactionalTree<K1, V1, C1>,
+        &TypedTransactionalTree<K2, V2, C2>,
+    ) -> ConflictableTransactionResult<A, ()>,
+{
+    let error = RefCell::new(None);
+    let result = (&a.inner, &b.inner).transaction(|(ta, tb)| {
+        f(
+            &TypedTransactionalTree {
+                tree: ta,
+                error: &error,
+                kv: PhantomData,
+            },
+            &TypedTransactionalTree {
+                tree: tb,
+                error: &error,
+                kv: PhantomData,
+            },
+        )
+    });
+    result.map_err(|e| match e {
+        TransactionError::Abort(()) => error
+            .into_inner()
+            .expect("transactions are only aborted by typed trees"),
+        TransactionError::Storage(e) => e.into(),
+    })
+}
@@ -1,3 +1,5 @@
+use chrono::{NaiveDate, Utc};
+
 pub fn chunk_with_size(s: &str) -> Vec<String> {
     const MAX_CHUNK_SIZE: usize = 2048;
 
@@ -14,3 +16,121 @@ pub fn chunk_with_size(s: &str) -> Vec<String> {
     }
     chunks
 }
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// Unix time range used to scope statistics, bounds are optional
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DateRange {
+    /// Inclusive lower bound
+    pub from: Option<i64>,
+    /// Exclusive upper bound
+    pub to: Option<i64>,
+}
+
+impl DateRange {
+    /// Parses `week`, `month` or `YYYY-MM-DD..YYYY-MM-DD` (either side may be omitted,
+    /// the start can't be after the end), no arguments mean all time
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        Self::parse_at(args, Utc::now().timestamp())
+    }
+
+    fn parse_at(args: &[&str], now: i64) -> Option<Self> {
+        fn day_start(date: &str) -> Option<i64> {
+            Some(
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .ok()?
+                    .and_hms(0, 0, 0)
+                    .timestamp(),
+            )
+        }
+        fn bound(date: &str) -> Option<Option<i64>> {
+            if date.is_empty() {
+                Some(None)
+            } else {
+                day_start(date).map(Some)
+            }
+        }
+
+        match args {
+            [] => Some(Self::default()),
+            ["week"] => Some(Self {
+                from: Some(now - 7 * DAY),
+                to: None,
+            }),
+            ["month"] => Some(Self {
+                from: Some(now - 30 * DAY),
+                to: None,
+            }),
+            [range] => {
+                let mut bounds = range.splitn(2, "..");
+                let from = bound(bounds.next()?)?;
+                let to = bound(bounds.next()?)?.map(|to| to + DAY);
+                match (from, to) {
+                    (Some(from), Some(to)) if from >= to => None,
+                    _ => Some(Self { from, to }),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_all_time(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    /// Undated entries are only part of the all time range
+    pub fn contains(&self, date: Option<i64>) -> bool {
+        match date {
+            None => self.is_all_time(),
+            Some(date) => {
+                self.from.map_or(true, |from| date >= from) && self.to.map_or(true, |to| date < to)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_range_test() {
+        let now = 1_587_000_000;
+        assert_eq!(DateRange::parse_at(&[], now), Some(DateRange::default()));
+        assert_eq!(
+            DateRange::parse_at(&["week"], now),
+            Some(DateRange {
+                from: Some(now - 7 * DAY),
+                to: None
+            })
+        );
+        assert_eq!(
+            DateRange::parse_at(&["2020-04-01..2020-04-30"], now),
+            Some(DateRange {
+                from: Some(1_585_699_200),
+                to: Some(1_588_291_200)
+            })
+        );
+        assert_eq!(
+            DateRange::parse_at(&["2020-04-01.."], now),
+            Some(DateRange {
+                from: Some(1_585_699_200),
+                to: None
+            })
+        );
+        assert_eq!(DateRange::parse_at(&["yesterday"], now), None);
+        assert_eq!(DateRange::parse_at(&["2020-04-01"], now), None);
+        assert_eq!(DateRange::parse_at(&["2020-04-30..2020-04-01"], now), None);
+        assert!(DateRange::parse_at(&["2020-04-01..2020-04-01"], now).is_some());
+    }
+
+    #[test]
+    fn date_range_contains_test() {
+        let april = DateRange::parse_at(&["2020-04-01..2020-04-30"], 0).unwrap();
+        assert!(april.contains(Some(1_585_699_200)));
+        assert!(!april.contains(Some(1_588_291_200)));
+        assert!(!april.contains(None));
+        assert!(DateRange::default().contains(None));
+    }
+}